@@ -23,6 +23,14 @@ impl Bios {
         }
     }
 
+    /// BIOS image built in memory, for tests.
+    #[cfg(test)]
+    pub fn from_data(data: Vec<u8>) -> Bios {
+        assert_eq!(data.len(), BIOS_SIZE as usize);
+
+        Bios { data }
+    }
+
     pub fn load8(&self, offset: u32) -> u8 {
         self.data[offset as usize]
     }
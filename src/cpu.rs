@@ -1,6 +1,9 @@
 use interconnect::Interconnect;
 use instruction::Instruction;
 
+/// Rough average cost of an instruction, we don't emulate the pipeline.
+const CYCLES_PER_INSTRUCTION: u32 = 2;
+
 pub struct Cpu {
     pc: u32,
     next_pc: u32,
@@ -60,6 +63,10 @@ impl Cpu {
         }
     }
 
+    pub fn interconnect_mut(&mut self) -> &mut Interconnect {
+        &mut self.inter
+    }
+
     fn reg(&self, index: u32) -> u32 {
         self.regs[index as usize]
     }
@@ -92,9 +99,16 @@ impl Cpu {
         self.delay_slot = self.branch;
         self.branch = false;
 
-        self.decode_and_execute(Instruction::new(instruction));
+        // IEc and the IM bit of the hardware interrupt line must both be set.
+        if self.inter.irq_active() && self.sr & 0x401 == 0x401 {
+            self.exception(Exception::Interrupt);
+        } else {
+            self.decode_and_execute(Instruction::new(instruction));
+        }
 
         self.regs = self.out_regs;
+
+        self.inter.tick(CYCLES_PER_INSTRUCTION);
     }
 
     fn store8(&mut self, addr: u32, value: u8) {
@@ -109,7 +123,7 @@ impl Cpu {
         self.inter.store16(addr, value)
     }
 
-    fn load16(&self, addr: u32) -> u16 {
+    fn load16(&mut self, addr: u32) -> u16 {
         self.inter.load16(addr)
     }
 
@@ -117,7 +131,7 @@ impl Cpu {
         self.inter.store32(addr, value)
     }
 
-    fn load32(&self, addr: u32) -> u32 {
+    fn load32(&mut self, addr: u32) -> u32 {
         self.inter.load32(addr)
     }
 
@@ -452,7 +466,7 @@ impl Cpu {
     fn op_mfc0(&mut self, rt: u32, rd: u32) {
         let value = match rd {
             12 => self.sr,
-            13 => self.cause,
+            13 => self.cause | ((self.inter.irq_active() as u32) << 10),
             14 => self.epc,
             _ => panic!("\n\nUnhandled MTC0 instruction: {:05b}\n\n", rd)
         };
@@ -478,8 +492,8 @@ impl Cpu {
 
         //Restore from exception mode.
         let mode = self.sr & 0x3f;
-        self.sr &= !0x3f;
-        self.sr |= mode << 2;
+        self.sr &= !0xf;
+        self.sr |= mode >> 2;
     }
 
     fn op_xor(&mut self, rs: u32, rt: u32, rd: u32) {
@@ -843,13 +857,13 @@ impl Cpu {
 
     fn exception(&mut self, cause: Exception) {
         let handler = match self.sr & (1 << 22) != 0 {
-            true => 0xfbc00180,
+            true => 0xbfc00180,
             false => 0x80000080,
         };
 
         //Switch to exception mode.
         let mode = self.sr & 0x3f;
-        self.sr &= 0x3f;
+        self.sr &= !0x3f;
         self.sr |= (mode << 2) & 0x3f;
 
         self.cause = (cause as u32) << 2;
@@ -866,6 +880,7 @@ impl Cpu {
 }
 
 enum Exception {
+    Interrupt = 0x0,
     SysCall = 0x8,
     Overflow = 0xc,
     LoadAddressError = 0x4,
@@ -874,3 +889,69 @@ enum Exception {
     CoprocessorError = 0xb,
     IllegalInstruction = 0xa,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Cpu, CYCLES_PER_INSTRUCTION};
+    use bios::Bios;
+    use interconnect::Interconnect;
+
+    const I_STAT: u32 = 0x1f801070;
+    const I_MASK: u32 = 0x1f801074;
+
+    /// CPU spinning on `j 0xbfc00000` at the reset vector.
+    fn spinning_cpu() -> Cpu {
+        let mut bios = vec![0; 512 * 1024];
+
+        bios[0..4].copy_from_slice(&0x0bf00000u32.to_le_bytes());
+
+        let mut cpu = Cpu::new(Interconnect::new(Bios::from_data(bios)));
+
+        // No exception taken yet.
+        cpu.cause = 0;
+
+        cpu
+    }
+
+    fn cause(cpu: &mut Cpu) -> u32 {
+        cpu.op_mfc0(8, 13);
+
+        cpu.load.1
+    }
+
+    #[test]
+    fn vblank_once_per_ntsc_frame() {
+        let mut cpu = spinning_cpu();
+
+        // 263 lines of 3413 GPU cycles, the GPU running at 11/7 times the
+        // CPU clock.
+        let frame_cycles = (263 * 3413 * 7u32).div_ceil(11);
+
+        let mut cycles = 0;
+        let mut vblanks = 0;
+
+        while cycles < frame_cycles {
+            cpu.run_next_instruction();
+            cycles += CYCLES_PER_INSTRUCTION;
+
+            if cpu.inter.load32(I_STAT) & 1 != 0 {
+                vblanks += 1;
+
+                if vblanks == 1 {
+                    // Masked: pending in I_STAT but not seen by the CPU.
+                    assert!(cause(&mut cpu) & (1 << 10) == 0);
+
+                    cpu.inter.store32(I_MASK, 1);
+
+                    assert!(cause(&mut cpu) & (1 << 10) != 0);
+
+                    cpu.inter.store32(I_MASK, 0);
+                }
+
+                cpu.inter.store32(I_STAT, 0);
+            }
+        }
+
+        assert_eq!(vblanks, 1);
+    }
+}
@@ -0,0 +1,390 @@
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use gpu::{rgb_from_15bit, Frame};
+use rasterizer::{DrawArea, DrawState, Texture, TextureDepth, Vertex};
+use renderer::Command;
+use vram::{VRam, VRAM_HEIGHT, VRAM_WIDTH};
+
+/// Outline colours of the areas shown on the VRAM image.
+const DISPLAY_COLOR: [u8; 3] = [0xff, 0xff, 0xff];
+const DRAWING_AREA_COLOR: [u8; 3] = [0xff, 0xff, 0x00];
+const PAGE_COLOR: [u8; 3] = [0x00, 0xc0, 0xff];
+const CLUT_COLOR: [u8; 3] = [0xff, 0x80, 0x00];
+
+/// Size of the squares each CLUT entry is drawn as.
+const CLUT_ENTRY_SIZE: u32 = 4;
+
+/// Collects what the GPU draws during a frame and writes it out as images
+/// and a text listing at the end of the frame:
+///
+/// - `frameNNNNN_vram.png`: VRAM with the display area, drawing areas,
+///   texture pages, CLUTs and primitive bounding boxes outlined.
+/// - `frameNNNNN_cluts.png`: every CLUT used, one row each.
+/// - `frameNNNNN.txt`: the primitives with their decoded parameters.
+pub struct DebugView {
+    dir: PathBuf,
+    commands: Vec<Command>,
+}
+
+impl DebugView {
+    pub fn new(dir: PathBuf) -> io::Result<DebugView> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(DebugView {
+            dir,
+            commands: Vec::new(),
+        })
+    }
+
+    /// Keeps `command` for the frame's listing. Upload rows and cache
+    /// flushes aren't worth showing.
+    pub fn push(&mut self, command: &Command) {
+        match *command {
+            Command::Store { .. } | Command::ClearCache => (),
+            _ => self.commands.push(command.clone()),
+        }
+    }
+
+    /// Writes the files for `frame` and starts collecting the next one.
+    /// `display` is the part of VRAM being shown.
+    pub fn end_frame(&mut self, frame: u32, vram: &VRam, display: DrawArea) -> io::Result<()> {
+        let name = |suffix: &str| self.dir.join(format!("frame{:05}{}", frame, suffix));
+
+        self.vram_image(vram, display).save(name("_vram.png"))?;
+
+        let cluts = self.cluts();
+
+        if !cluts.is_empty() {
+            clut_image(vram, &cluts).save(name("_cluts.png"))?;
+        }
+
+        fs::write(name(".txt"), self.listing())?;
+
+        self.commands.clear();
+
+        Ok(())
+    }
+
+    fn vram_image(&self, vram: &VRam, display: DrawArea) -> Frame {
+        let mut frame = Frame::new(VRAM_WIDTH as u32, VRAM_HEIGHT as u32);
+
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                frame.set_pixel(x, y, rgb_from_15bit(vram.load16(x as u16, y as u16)));
+            }
+        }
+
+        // Primitives first so that the areas stay visible on top of them.
+        for command in &self.commands {
+            let (area, color) = match bounding_box(command) {
+                Some(b) => b,
+                None => continue,
+            };
+
+            outline(&mut frame, area, color);
+        }
+
+        for texture in self.textures() {
+            let width = match texture.depth {
+                TextureDepth::T4Bit => 64,
+                TextureDepth::T8Bit => 128,
+                TextureDepth::T15Bit => 256,
+            };
+
+            let page = DrawArea {
+                left: texture.page_x as i32,
+                top: texture.page_y as i32,
+                right: texture.page_x as i32 + width - 1,
+                bottom: texture.page_y as i32 + 255,
+            };
+
+            outline(&mut frame, page, PAGE_COLOR);
+        }
+
+        for &(x, y, depth) in &self.cluts() {
+            let clut = DrawArea {
+                left: x as i32,
+                top: y as i32,
+                right: x as i32 + clut_size(depth) as i32 - 1,
+                bottom: y as i32,
+            };
+
+            outline(&mut frame, clut, CLUT_COLOR);
+        }
+
+        let mut areas: Vec<(i32, i32, i32, i32)> = self.commands.iter()
+            .filter_map(|c| c.draw_state())
+            .map(|s| (s.area.left, s.area.top, s.area.right, s.area.bottom))
+            .collect();
+
+        areas.sort();
+        areas.dedup();
+
+        for (left, top, right, bottom) in areas {
+            outline(&mut frame, DrawArea { left, top, right, bottom }, DRAWING_AREA_COLOR);
+        }
+
+        outline(&mut frame, display, DISPLAY_COLOR);
+
+        frame
+    }
+
+    /// Distinct textures sampled this frame, the texture window and raw
+    /// flag aside.
+    fn textures(&self) -> Vec<Texture> {
+        let mut textures: Vec<Texture> = Vec::new();
+
+        for command in &self.commands {
+            if let Some(texture) = command_texture(command) {
+                let known = textures.iter().any(|t| {
+                    t.page_x == texture.page_x && t.page_y == texture.page_y && t.depth == texture.depth
+                });
+
+                if !known {
+                    textures.push(texture);
+                }
+            }
+        }
+
+        textures
+    }
+
+    /// Distinct CLUTs used this frame as position and depth.
+    fn cluts(&self) -> Vec<(u16, u16, TextureDepth)> {
+        let mut cluts = Vec::new();
+
+        for command in &self.commands {
+            let texture = match command_texture(command) {
+                Some(t) => t,
+                None => continue,
+            };
+
+            let clut = (texture.clut_x, texture.clut_y, texture.depth);
+
+            if texture.depth != TextureDepth::T15Bit && !cluts.contains(&clut) {
+                cluts.push(clut);
+            }
+        }
+
+        cluts
+    }
+
+    fn listing(&self) -> String {
+        let mut text = String::new();
+
+        for (i, command) in self.commands.iter().enumerate() {
+            let _ = writeln!(text, "{:5} {}", i, describe(command));
+        }
+
+        text
+    }
+}
+
+/// Entries of each CLUT side by side, one CLUT per row.
+fn clut_image(vram: &VRam, cluts: &[(u16, u16, TextureDepth)]) -> Frame {
+    let mut frame = Frame::new(256 * CLUT_ENTRY_SIZE, cluts.len() as u32 * (CLUT_ENTRY_SIZE + 1));
+
+    for (row, &(x, y, depth)) in cluts.iter().enumerate() {
+        for entry in 0..clut_size(depth) {
+            let color = rgb_from_15bit(vram.load16(x + entry as u16, y));
+
+            for dy in 0..CLUT_ENTRY_SIZE {
+                for dx in 0..CLUT_ENTRY_SIZE {
+                    let px = entry * CLUT_ENTRY_SIZE + dx;
+                    let py = row as u32 * (CLUT_ENTRY_SIZE + 1) + dy;
+
+                    frame.set_pixel(px, py, color);
+                }
+            }
+        }
+    }
+
+    frame
+}
+
+fn clut_size(depth: TextureDepth) -> u32 {
+    match depth {
+        TextureDepth::T4Bit => 16,
+        _ => 256,
+    }
+}
+
+/// Draws the edges of `area`, clipped to the frame.
+fn outline(frame: &mut Frame, area: DrawArea, color: [u8; 3]) {
+    let (width, height) = (frame.width as i32, frame.height as i32);
+
+    let mut plot = |x: i32, y: i32| {
+        if x >= 0 && x < width && y >= 0 && y < height {
+            frame.set_pixel(x as u32, y as u32, color);
+        }
+    };
+
+    for x in area.left..=area.right {
+        plot(x, area.top);
+        plot(x, area.bottom);
+    }
+
+    for y in area.top..=area.bottom {
+        plot(area.left, y);
+        plot(area.right, y);
+    }
+}
+
+fn command_texture(command: &Command) -> Option<Texture> {
+    match *command {
+        Command::Triangle(_, _, texture) => texture,
+        Command::Quad(_, _, texture) => texture,
+        Command::Rectangle { texture, .. } => texture,
+        _ => None,
+    }
+}
+
+/// VRAM area touched by a command and the colour it's outlined with.
+fn bounding_box(command: &Command) -> Option<(DrawArea, [u8; 3])> {
+    let around = |vertices: &[Vertex]| {
+        let mut area = DrawArea {
+            left: vertices[0].x,
+            top: vertices[0].y,
+            right: vertices[0].x,
+            bottom: vertices[0].y,
+        };
+
+        for v in vertices {
+            area = area.union(&DrawArea { left: v.x, top: v.y, right: v.x, bottom: v.y });
+        }
+
+        area
+    };
+
+    let rectangle = |x: u16, y: u16, (width, height): (u16, u16)| {
+        DrawArea {
+            left: x as i32,
+            top: y as i32,
+            right: x as i32 + width as i32 - 1,
+            bottom: y as i32 + height as i32 - 1,
+        }
+    };
+
+    match *command {
+        Command::Triangle(_, v, _) => Some((around(&v), [0xff, 0x00, 0x00])),
+        Command::Quad(_, v, _) => Some((around(&v), [0xff, 0x00, 0xff])),
+        Command::Rectangle { vertex, size: (width, height), .. } => {
+            let area = DrawArea {
+                left: vertex.x,
+                top: vertex.y,
+                right: vertex.x + width - 1,
+                bottom: vertex.y + height - 1,
+            };
+
+            Some((area, [0x00, 0xff, 0x00]))
+        },
+        Command::Line(_, a, b) => Some((around(&[a, b]), [0x00, 0xff, 0xff])),
+        Command::Fill { position: (x, y), size, .. } => Some((rectangle(x, y, size), [0x00, 0x00, 0xff])),
+        Command::Copy { dst: (x, y), size, .. } => Some((rectangle(x, y, size), [0x80, 0x80, 0x80])),
+        Command::Store { .. } | Command::ClearCache => None,
+    }
+}
+
+/// One line of the primitive listing.
+fn describe(command: &Command) -> String {
+    match *command {
+        Command::Triangle(state, v, texture) => {
+            format!("triangle {}{}{}", vertices(&v, texture.is_some()), texture_params(texture), state_params(&state))
+        },
+        Command::Quad(state, v, texture) => {
+            format!("quad {}{}{}", vertices(&v, texture.is_some()), texture_params(texture), state_params(&state))
+        },
+        Command::Rectangle { state, vertex, size: (width, height), texture, flip } => {
+            format!("rectangle {} size {}x{} flip {:?}{}{}",
+                    vertices(&[vertex], texture.is_some()),
+                    width,
+                    height,
+                    flip,
+                    texture_params(texture),
+                    state_params(&state))
+        },
+        Command::Line(state, a, b) => {
+            format!("line {}{}", vertices(&[a, b], false), state_params(&state))
+        },
+        Command::Fill { position: (x, y), size: (width, height), color, .. } => {
+            format!("fill ({}, {}) size {}x{} color {:04x}", x, y, width, height, color)
+        },
+        Command::Copy { src, dst, size: (width, height), mask } => {
+            format!("copy {:?} to {:?} size {}x{} set mask {} check mask {}",
+                    src,
+                    dst,
+                    width,
+                    height,
+                    mask.set_mask,
+                    mask.check_mask)
+        },
+        Command::Store { .. } => "store".to_string(),
+        Command::ClearCache => "clear cache".to_string(),
+    }
+}
+
+fn vertices(vertices: &[Vertex], textured: bool) -> String {
+    let mut text = String::new();
+
+    for v in vertices {
+        let _ = write!(text, "({}, {}) #{:02x}{:02x}{:02x}", v.x, v.y, v.color.r, v.color.g, v.color.b);
+
+        if textured {
+            let _ = write!(text, " uv ({}, {})", v.u, v.v);
+        }
+
+        text.push(' ');
+    }
+
+    text.pop();
+
+    text
+}
+
+fn texture_params(texture: Option<Texture>) -> String {
+    let t = match texture {
+        Some(t) => t,
+        None => return String::new(),
+    };
+
+    let w = t.window;
+
+    format!(" texture page ({}, {}) {:?} clut ({}, {}) window mask ({}, {}) offset ({}, {}){}",
+            t.page_x,
+            t.page_y,
+            t.depth,
+            t.clut_x,
+            t.clut_y,
+            w.x_mask,
+            w.y_mask,
+            w.x_offset,
+            w.y_offset,
+            if t.raw { " raw" } else { "" })
+}
+
+fn state_params(state: &DrawState) -> String {
+    let a = state.area;
+
+    let mut text = format!(" area ({}, {})-({}, {})", a.left, a.top, a.right, a.bottom);
+
+    if let Some(mode) = state.semi_transparency {
+        let _ = write!(text, " {:?}", mode);
+    }
+
+    if state.dither {
+        text.push_str(" dither");
+    }
+
+    if state.set_mask {
+        text.push_str(" set mask");
+    }
+
+    if state.check_mask {
+        text.push_str(" check mask");
+    }
+
+    text
+}
@@ -1,5 +1,20 @@
 use channel::Channel;
 
+/// Default cap on the number of headers walked by one linked list transfer.
+/// Every node lives at a distinct word in RAM so a sane list can't be longer.
+pub const LINKED_LIST_MAX_PACKETS: u32 = 2 * 1024 * 1024 / 4;
+
+/// Default cap on the number of words sent to the port by one linked list
+/// transfer.
+pub const LINKED_LIST_MAX_WORDS: u32 = 4 * 1024 * 1024;
+
+/// Approximate CPU cycles the DMA spends reading a linked list header and
+/// following the pointer to the next one, during which the CPU is stalled.
+pub const LINKED_LIST_HEADER_CYCLES: u32 = 10;
+
+/// CPU cycles per word moved by the DMA.
+pub const WORD_CYCLES: u32 = 1;
+
 pub struct Dma {
     control: u32,
 
@@ -13,6 +28,9 @@ pub struct Dma {
     irq_dummy: u8,
 
     channels: [Channel; 7],
+
+    linked_list_max_packets: u32,
+    linked_list_max_words: u32,
 }
 
 impl Dma {
@@ -30,6 +48,9 @@ impl Dma {
             irq_dummy: 0,
 
             channels: [Channel::new(); 7],
+
+            linked_list_max_packets: LINKED_LIST_MAX_PACKETS,
+            linked_list_max_words: LINKED_LIST_MAX_WORDS,
         }
     }
 
@@ -73,6 +94,17 @@ impl Dma {
         self.control = value;
     }
 
+    pub fn linked_list_limits(&self) -> (u32, u32) {
+        (self.linked_list_max_packets, self.linked_list_max_words)
+    }
+
+    /// Caps a single linked list transfer so that a corrupt ordering table
+    /// can't keep the emulator walking forever.
+    pub fn set_linked_list_limits(&mut self, max_packets: u32, max_words: u32) {
+        self.linked_list_max_packets = max_packets;
+        self.linked_list_max_words = max_words;
+    }
+
     pub fn channel(&self, port: Port) -> &Channel {
         &self.channels[port as usize]
     }
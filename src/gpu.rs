@@ -1,3 +1,55 @@
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use image;
+use debug_view::DebugView;
+use gpu_stats::{FrameStats, GpuStats};
+use texture_pack::TexturePack;
+use renderer::{Command, MaskSettings, Renderer};
+use vram::{VRam, VRAM_WIDTH, VRAM_HEIGHT};
+use gpu_dump::{Event, RecordRequest, Recorder};
+use rasterizer::{Color, Vertex, DrawArea, DrawState, DrawStats, SemiTransparency};
+use rasterizer::{Texture, TextureDepth, TextureWindow};
+
+/// GPU cycles per scanline.
+const NTSC_CYCLES_PER_LINE: u32 = 3413;
+const PAL_CYCLES_PER_LINE: u32 = 3406;
+
+/// Position of the horizontal blanking within a scanline, in GPU cycles.
+const HBLANK_END: u32 = 0x260;
+const HBLANK_START: u32 = 0xc60;
+
+/// Rough GPU cycle costs of setting up a primitive, used to model the time
+/// spent drawing.
+const POLYGON_SETUP_CYCLES: u32 = 64;
+const LINE_SETUP_CYCLES: u32 = 16;
+const RECTANGLE_SETUP_CYCLES: u32 = 16;
+
+/// Video signals produced while advancing the GPU clock.
+pub struct VideoSignals {
+    /// Dot clock ticks, at the rate of the current horizontal resolution.
+    pub dot_clocks: u32,
+    pub hblank_starts: u32,
+    pub vblank_starts: u32,
+    pub in_hblank: bool,
+    pub in_vblank: bool,
+}
+
+/// Where the video clock is and how long the current drawing has left,
+/// saved in GPU dumps so that a replay sees the same vblanks and busy
+/// periods.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimingState {
+    pub line: u16,
+    pub line_cycles: u32,
+    pub clock_fraction: u32,
+    pub dot_fraction: u32,
+    pub top_field: bool,
+    pub in_vblank: bool,
+    pub busy_cycles: u32,
+}
+
 pub struct Gpu {
     page_base_x: u8,
     page_base_y: u8,
@@ -30,6 +82,9 @@ pub struct Gpu {
     display_disable: bool,
 
     interrupt: bool,
+    /// Set when GP0 0x1F raises the interrupt, until the interconnect
+    /// forwards it to the interrupt controller.
+    irq_pending: bool,
 
     dma_direction: DmaDirection,
 
@@ -64,6 +119,58 @@ pub struct Gpu {
     gp0_command_method: fn(&mut Gpu),
 
     gp0_mode: Gp0Mode,
+
+    gp0_fifo: CommandFifo,
+    /// GPU cycles left before the current command is done drawing.
+    busy_cycles: u32,
+
+    polyline: PolyLine,
+
+    image_load: ImageTransfer,
+    image_store: ImageTransfer,
+    /// Pixels of the upload row in progress and the VRAM position of the
+    /// first one, sent to the renderer once the row is complete.
+    upload_row: Vec<u16>,
+    upload_row_position: (u16, u16),
+
+    read_latch: u32,
+
+    renderer: Renderer,
+
+    /// How `display_frame` shows 480 line interlaced images.
+    deinterlacing: Deinterlacing,
+
+    /// Frontend override, dithering is only applied when both this and the
+    /// GP0 0xE1 bit are set.
+    dithering_allowed: bool,
+
+    /// Texture cache emulation is enabled, the renderer must then be told
+    /// when the cache is flushed.
+    texture_cache: bool,
+
+    texture_disable_allowed: bool,
+
+    /// Current scanline and how far into it we are, in GPU cycles.
+    line: u16,
+    line_cycles: u32,
+    /// Leftover CPU cycles when converting to the GPU clock, in 1/7th.
+    clock_fraction: u32,
+    /// GPU cycles into the current dot.
+    dot_fraction: u32,
+
+    in_vblank: bool,
+
+    /// Frames started since power on.
+    frame: u32,
+
+    /// Recording waiting for its start frame.
+    record_request: Option<RecordRequest>,
+    recorder: Option<Recorder>,
+
+    /// Per-frame diagnostic output.
+    debug_view: Option<DebugView>,
+
+    stats: GpuStats,
 }
 
 impl Gpu {
@@ -100,6 +207,7 @@ impl Gpu {
             display_disable: true,
 
             interrupt: false,
+            irq_pending: false,
 
             dma_direction: DmaDirection::Off,
 
@@ -123,17 +231,51 @@ impl Gpu {
             display_vram_x_start: 0,
             display_vram_y_start: 0,
 
-            display_horizontal_start: 0,
-            display_horizontal_end: 0,
+            display_horizontal_start: 0x200,
+            display_horizontal_end: 0xc00,
 
-            display_line_start: 0,
-            display_line_end: 0,
+            display_line_start: 0x10,
+            display_line_end: 0x100,
 
             gp0_command: CommandBuffer::new(),
             gp0_command_remaining: 0,
             gp0_command_method: Gpu::gp0_nop,
 
             gp0_mode: Gp0Mode::Command,
+
+            gp0_fifo: CommandFifo::new(),
+            busy_cycles: 0,
+
+            polyline: PolyLine::new(),
+
+            image_load: ImageTransfer::new(),
+            image_store: ImageTransfer::new(),
+            upload_row: Vec::new(),
+            upload_row_position: (0, 0),
+
+            read_latch: 0,
+
+            renderer: Renderer::new(),
+
+            deinterlacing: Deinterlacing::Weave,
+            dithering_allowed: true,
+            texture_cache: false,
+
+            texture_disable_allowed: false,
+
+            line: 0,
+            line_cycles: 0,
+            clock_fraction: 0,
+            dot_fraction: 0,
+            // Line 0 is above the default display range.
+            in_vblank: true,
+
+            frame: 0,
+
+            record_request: None,
+            recorder: None,
+            debug_view: None,
+            stats: GpuStats::new(),
         }
     }
 
@@ -148,26 +290,32 @@ impl Gpu {
         r |= (self.draw_to_display as u32) << 10;
         r |= (self.force_set_mask_bit as u32) << 11;
         r |= (self.preserve_masked_pixels as u32) << 12;
-        r |= (self.field as u32) << 13;
+        // Without interlacing the field bit reads as always set.
+        r |= (!self.interlaced as u32 | self.field as u32) << 13;
         r |= (self.textrure_disable as u32) << 15;
         r |= self.hres.into_status();
-        //r |= (self.vres as u32) << 19;
+        r |= (self.vres as u32) << 19;
         r |= (self.vmode as u32) << 20;
         r |= (self.display_depth as u32) << 21;
         r |= (self.interlaced as u32) << 22;
         r |= (self.display_disable as u32) << 23;
         r |= (self.interrupt as u32) << 24;
 
-        r |= 1 << 26;
-        r |= 1 << 27;
-        r |= 1 << 28;
+        let idle = self.gp0_mode == Gp0Mode::Command
+            && self.gp0_command_remaining == 0
+            && self.gp0_fifo.is_empty()
+            && self.busy_cycles == 0;
+
+        r |= (idle as u32) << 26;
+        r |= ((self.image_store.remaining() > 0) as u32) << 27;
+        r |= (!self.gp0_fifo.is_full() as u32) << 28;
 
         r |= (self.dma_direction as u32) << 29;
-        r |= 0 << 31;
-        
+        r |= (self.odd_line() as u32) << 31;
+
         let dma_request = match self.dma_direction {
             DmaDirection::Off => 0,
-            DmaDirection::Fifo => 1,
+            DmaDirection::Fifo => !self.gp0_fifo.is_full() as u32,
             DmaDirection::CpuToGpu0 => (r >> 28) & 1,
             DmaDirection::VRamToCpu => (r >> 27) & 1,
         };
@@ -177,329 +325,1522 @@ impl Gpu {
         r
     }
 
-    pub fn gp0(&mut self, value: u32) {
-        if self.gp0_command_remaining == 0 {
-            let opcode = (value >> 24) & 0xff;
-
-            let (length, method) = match opcode {
-                0x00 => (1, Gpu::gp0_nop as fn(&mut Gpu)),
-                0x01 => (1, Gpu::gp0_clear_cache as fn(&mut Gpu)),
-                0x28 => (5, Gpu::gp0_quad_mono_opaque as fn(&mut Gpu)),
-                0x2c => (9, Gpu::gp0_quad_texture_blend_opaque as fn(&mut Gpu)),
-                0x30 => (6, Gpu::gp0_triangle_shaded_opaque as fn(&mut Gpu)),
-                0x38 => (8, Gpu::gp0_quad_shaded_opaque as fn(&mut Gpu)),
-                0xa0 => (3, Gpu::gp0_image_load as fn(&mut Gpu)),
-                0xc0 => (3, Gpu::gp0_image_store as fn(&mut Gpu)),
-                0xe1 => (1, Gpu::gp0_draw_mode as fn(&mut Gpu)),
-                0xe2 => (1, Gpu::gp0_texture_window as fn(&mut Gpu)),
-                0xe3 => (1, Gpu::gp0_drawing_area_top_left as fn(&mut Gpu)),
-                0xe4 => (1, Gpu::gp0_drawing_area_bottom_right as fn(&mut Gpu)),
-                0xe5 => (1, Gpu::gp0_drawing_offset as fn(&mut Gpu)),
-                0xe6 => (1, Gpu::gp0_mask_bit_setting as fn(&mut Gpu)),
-                _ => panic!("Unhandled GPU0 command {:08x}", value),
-            };
+    /// GPUSTAT bit 31. In 480 line interlaced mode it follows the field
+    /// being drawn, otherwise it toggles every scanline. It always reads 0
+    /// during vblank.
+    fn odd_line(&self) -> bool {
+        if self.in_vblank {
+            return false;
+        }
 
-            self.gp0_command_remaining = length;
-            self.gp0_command_method = method;
+        match (self.interlaced, self.vres) {
+            (true, VerticalRes::Y480Lines) => self.field == Field::Bottom,
+            _ => self.line & 1 != 0,
+        }
+    }
 
-            self.gp0_command.clear();
+    /// Advances the video clock by `cpu_cycles` CPU cycles and returns the
+    /// signals the rest of the console sees from it.
+    pub fn tick(&mut self, cpu_cycles: u32) -> VideoSignals {
+        // The GPU runs at 11/7 times the CPU clock.
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.tick(cpu_cycles);
         }
-        
-        self.gp0_command_remaining -= 1;
 
-        match self.gp0_mode {
-            Gp0Mode::Command => {
-                self.gp0_command.push_word(value);
+        let cycles = cpu_cycles * 11 + self.clock_fraction;
 
-                if self.gp0_command_remaining == 0 {
-                    (self.gp0_command_method)(self);
-                }
-            },
-            Gp0Mode::ImageLoad => {
-                if self.gp0_command_remaining == 0 {
-                    self.gp0_mode = Gp0Mode::Command;
-                }
-            }
-        }
+        self.clock_fraction = cycles % 7;
 
-        
-        
-        
-    }
+        let mut remaining = cycles / 7;
 
-    pub fn gp1(&mut self, value: u32) {
-        let opcode = (value >> 24) & 0xff;
+        // Cycles left once a command is done go to the next one in the
+        // FIFO, so that the timing doesn't depend on how the time is split
+        // between ticks.
+        let mut drawing = remaining;
 
-        match opcode {
-            0x00 => self.gp1_reset(value),
-            0x01 => self.gp1_reset_command_buffer(),
-            0x02 => self.gp1_acknowledge_irq(),
-            0x03 => self.gp1_display_enable(value),
-            0x04 => self.gp1_dma_direction(value),
-            0x05 => self.gp1_display_vram_start(value),
-            0x06 => self.gp1_display_horizontal_range(value),
-            0x07 => self.gp1_display_vertical_range(value),
-            0x08 => self.gp1_display_mode(value),
-            _ => panic!("Unhandled GPU1 command {:08x}", opcode),
-        }
-    }
+        while self.busy_cycles > 0 && drawing > 0 {
+            let step = drawing.min(self.busy_cycles);
 
-    fn gp0_nop(&mut self) {
+            self.busy_cycles -= step;
+            drawing -= step;
 
-    }
+            self.run_fifo();
+        }
 
-    fn gp0_clear_cache(&mut self) {
-        println!("clear_cache!!!");
-    }
+        let (_, dot_clock) = self.hres.dimensions();
 
-    fn gp0_image_store(&mut self) {
-        let res = self.gp0_command[2];
+        let dots = self.dot_fraction + remaining;
 
-        let width = res & 0xffff;
-        let height = res >> 16;
+        self.dot_fraction = dots % dot_clock;
 
-        println!("image_store!!!");
-    }
+        let mut signals = VideoSignals {
+            dot_clocks: dots / dot_clock,
+            hblank_starts: 0,
+            vblank_starts: 0,
+            in_hblank: false,
+            in_vblank: false,
+        };
 
-    fn gp0_image_load(&mut self) {
-        let res = self.gp0_command[2];
+        let cycles_per_line = match self.vmode {
+            VMode::NTSC => NTSC_CYCLES_PER_LINE,
+            VMode::PAL => PAL_CYCLES_PER_LINE,
+        };
 
-        let width = res & 0xffff;
-        let height = res >> 16;
+        while remaining > 0 {
+            let step = remaining.min(cycles_per_line - self.line_cycles);
 
-        let image_size = width * height;
+            if self.line_cycles < HBLANK_START && self.line_cycles + step >= HBLANK_START {
+                signals.hblank_starts += 1;
+            }
 
-        let image_size = (image_size + 1) & !1;
+            self.line_cycles += step;
+            remaining -= step;
 
-        self.gp0_command_remaining = image_size / 2;
-        self.gp0_mode = Gp0Mode::ImageLoad;
-    }
+            if self.line_cycles == cycles_per_line {
+                self.line_cycles = 0;
+                self.next_line(&mut signals);
+            }
+        }
 
-    fn gp0_quad_mono_opaque(&mut self) {
-        println!("Draw Mono!!!");
-    }
+        signals.in_hblank = self.line_cycles < HBLANK_END || self.line_cycles >= HBLANK_START;
+        signals.in_vblank = self.in_vblank;
 
-    fn gp0_quad_texture_blend_opaque(&mut self) {
-        println!("Draw texture blend!!!");
-    }
+        // Recordings start between ticks, the dump's timing would otherwise
+        // miss the rest of this one.
+        let start = match self.record_request {
+            Some(ref r) => r.start_frame <= self.frame,
+            None => false,
+        };
 
-    fn gp0_triangle_shaded_opaque(&mut self) {
-        println!("Draw triangle Shaded!!!");
-    }
+        if start {
+            self.start_recording();
+        }
 
-    fn gp0_quad_shaded_opaque(&mut self) {
-        println!("Draw Shaded!!!");
+        signals
     }
 
-    fn gp0_draw_mode(&mut self) {
-        let value = self.gp0_command[0];
+    fn next_line(&mut self, signals: &mut VideoSignals) {
+        let lines_per_frame = match self.vmode {
+            VMode::NTSC => 263,
+            VMode::PAL => 314,
+        };
 
-        self.page_base_x = (value & 0xf) as u8;
-        self.page_base_y = ((value >> 4) & 1) as u8;
+        self.line += 1;
 
-        self.semi_transparency = ((value >> 5) & 3) as u8;
+        if self.line == lines_per_frame {
+            self.line = 0;
+        }
 
-        self.texture_depth = match (value >> 7) & 3 {
-            0 => TextureDepth::T4Bit,
-            1 => TextureDepth::T8Bit,
-            2 => TextureDepth::T15Bit,
-            n => panic!("Unhandled texture Depth"),
+        // The vertical display range set through GP1 0x07 decides where
+        // vblank starts and ends. An empty range leaves the standard one.
+        let (start, end) = match self.display_line_end > self.display_line_start {
+            true => (self.display_line_start, self.display_line_end),
+            false => match self.vmode {
+                VMode::NTSC => (0x10, 0x100),
+                VMode::PAL => (0x23, 0x123),
+            },
         };
 
-        self.dithering = ((value >> 9) & 1) != 0;
-        self.draw_to_display = ((value >> 10) & 1) != 0;
-        self.textrure_disable = ((value >> 11) & 1) != 0;
-        self.rectangle_texture_x_flip = ((value >> 12) & 1) != 0;
-        self.rectangle_texture_y_flip = ((value >> 13) & 1) != 0;
-    }
+        let vblank = self.line < start || self.line >= end;
 
-    fn gp0_texture_window(&mut self) {
-        let value = self.gp0_command[0];
+        if vblank && !self.in_vblank {
+            signals.vblank_starts += 1;
 
-        self.texture_window_x_mask = (value & 0x1f) as u8;
-        self.texture_window_y_mask = ((value >> 5) & 0x1f) as u8;
-        self.texture_window_x_offset = ((value >> 10) & 0x1f) as u8;
-        self.texture_window_y_offset = ((value >> 15) & 0x1f) as u8;
-    }
+            self.frame_end();
 
-    fn gp0_drawing_area_top_left(&mut self) {
-        let value = self.gp0_command[0];
+            if self.interlaced {
+                self.field = match self.field {
+                    Field::Top => Field::Bottom,
+                    Field::Bottom => Field::Top,
+                };
+            }
+        }
 
-        self.drawing_area_left = (value & 0x3ff) as u16;
-        self.drawing_area_top = ((value  >> 10)& 0x3ff) as u16;
+        self.in_vblank = vblank;
     }
 
-    fn gp0_drawing_area_bottom_right(&mut self) {
-        let value = self.gp0_command[0];
+    /// Builds the picture a TV would show: the visible part of the scanlines
+    /// with the image placed according to the display ranges and black
+    /// borders around it.
+    pub fn display_frame(&mut self) -> Frame {
+        self.renderer.sync();
 
-        self.drawing_area_right = (value & 0x3ff) as u16;
-        self.drawing_area_bottom = ((value  >> 10)& 0x3ff) as u16;
-    }
+        let vram = self.renderer.vram();
 
-    fn gp0_drawing_offset(&mut self) {
-        let value = self.gp0_command[0];
+        let (width, dot_clock) = self.hres.dimensions();
 
-        let x = (value & 0x7ff) as u16;
-        let y = ((value  >> 11)& 0x7ff) as u16;
+        // First visible GPU cycle and scanline and number of visible lines.
+        let (tv_x_start, tv_y_start, tv_lines) = match self.vmode {
+            VMode::NTSC => (0x260, 16, 240),
+            VMode::PAL => (0x274, 20, 288),
+        };
 
-        self.drawing_x_offset = ((x << 5) as i16) >> 5;
-        self.drawing_y_offset = ((y << 5) as i16) >> 5;
-    }
+        let interlaced_480 = self.interlaced && matches!(self.vres, VerticalRes::Y480Lines);
 
-    fn gp0_mask_bit_setting(&mut self) {
-        let value = self.gp0_command[0];
+        let line_scale = if interlaced_480 { 2 } else { 1 };
 
-        self.force_set_mask_bit = (value & 1) != 0;
-        self.preserve_masked_pixels = (value & 2) != 0;
-    }
+        let scale = vram.scale() as u32;
 
-    fn gp1_reset(&mut self, value: u32) {
-            self.page_base_x = 0;
-            self.page_base_y = 0;
+        let mut frame = Frame::new(width * scale, tv_lines * line_scale * scale);
 
-            self.semi_transparency = 0;
+        if self.display_disable {
+            return frame;
+        }
 
-            self.texture_depth = TextureDepth::T4Bit;
-            
-            self.dithering = false;
+        let x1 = self.display_horizontal_start as i32;
+        let x2 = self.display_horizontal_end as i32;
+        let y1 = self.display_line_start as i32;
+        let y2 = self.display_line_end as i32;
 
-            self.draw_to_display = false;
+        let dot_clock = dot_clock as i32;
 
-            self.force_set_mask_bit = false;
+        // The GPU rounds the number of pixels per line to a multiple of 4.
+        let image_width = (((x2 - x1) / dot_clock + 2) & !3).max(0);
+        let image_x = (x1 - tv_x_start) / dot_clock;
 
-            self.preserve_masked_pixels = false;
+        let image_lines = (y2 - y1).max(0) * line_scale as i32;
+        let image_y = (y1 - tv_y_start) * line_scale as i32;
 
-            self.field = Field::Top;
+        for y in 0..frame.height {
+            let line = (y / scale) as i32 - image_y;
 
-            self.textrure_disable = false;
+            if line < 0 || line >= image_lines {
+                continue;
+            }
 
-            self.hres = HorisontalRes::from_fields(0, 0);
-            self.vres = VerticalRes::Y240Lines;
+            // Display lines the row shows, averaged when they differ.
+            let (a, b) = match interlaced_480 {
+                true => self.deinterlacing.lines(line, 1 - self.drawn_field()),
+                false => (line, line),
+            };
 
-            self.vmode = VMode::NTSC;
+            let vram_y = |line: i32| self.display_vram_y_start.wrapping_add(line as u16);
 
-            self.display_depth = DisplayDepth::D15Bits;
+            for x in 0..frame.width {
+                let column = (x / scale) as i32 - image_x;
 
-            self.interlaced = false;
+                if column < 0 || column >= image_width {
+                    continue;
+                }
 
-            self.display_disable = true;
+                let sub_pixel = ((x % scale) as u16, (y % scale) as u16);
 
-            self.interrupt = false;
+                let color = self.display_pixel(&vram, column as u16, vram_y(a), sub_pixel);
 
-            self.dma_direction = DmaDirection::Off;
+                let color = match a == b {
+                    true => color,
+                    false => {
+                        let other = self.display_pixel(&vram, column as u16, vram_y(b), sub_pixel);
 
-            self.rectangle_texture_x_flip = false;
-            self.rectangle_texture_y_flip = false;
+                        let mix = |i: usize| ((color[i] as u16 + other[i] as u16) / 2) as u8;
 
-            self.texture_window_x_mask = 0;
-            self.texture_window_y_mask = 0;
+                        [mix(0), mix(1), mix(2)]
+                    },
+                };
 
-            self.texture_window_x_offset = 0;
-            self.texture_window_y_offset = 0;
+                frame.set_pixel(x, y, color);
+            }
+        }
 
-            self.drawing_area_left = 0;
-            self.drawing_area_top = 0;
-            self.drawing_area_right = 0;
-            self.drawing_area_bottom = 0;
+        frame
+    }
 
-            self.drawing_x_offset = 0;
-            self.drawing_y_offset = 0;
+    /// Renders the whole VRAM, or one texture page of it, for inspection.
+    pub fn vram_frame(&mut self, view: VRamView) -> Frame {
+        self.renderer.sync();
 
-            self.display_vram_x_start = 0;
-            self.display_vram_y_start = 0;
+        let vram = self.renderer.vram();
 
-            self.display_horizontal_start = 0;
-            self.display_horizontal_end = 0;
+        match view {
+            VRamView::Bits15 => {
+                let mut frame = Frame::new(VRAM_WIDTH as u32, VRAM_HEIGHT as u32);
 
-            self.display_line_start = 0;
-            self.display_line_end = 0;
-    }
+                for y in 0..frame.height {
+                    for x in 0..frame.width {
+                        let pixel = vram.load16(x as u16, y as u16);
 
-    fn gp1_reset_command_buffer(&mut self) {
-        self.gp0_command.clear();
-        self.gp0_command_remaining = 0;
-        self.gp0_mode = Gp0Mode::Command;
-    }
+                        frame.set_pixel(x, y, rgb_from_15bit(pixel));
+                    }
+                }
 
-    fn gp1_acknowledge_irq(&mut self) {
-        self.interrupt = false;
+                frame
+            },
+            VRamView::Bits24 => {
+                // 2048 bytes per line hold 682 whole pixels.
+                let mut frame = Frame::new((VRAM_WIDTH * 2 / 3) as u32, VRAM_HEIGHT as u32);
+
+                for y in 0..frame.height {
+                    for x in 0..frame.width {
+                        let color = pixel_24bit(&vram, 0, x as u16, y as u16);
+
+                        frame.set_pixel(x, y, color);
+                    }
+                }
+
+                frame
+            },
+            VRamView::Texture { page_x, page_y, depth, clut_x, clut_y } => {
+                let texture = Texture {
+                    page_x,
+                    page_y,
+                    depth,
+                    clut_x,
+                    clut_y,
+                    window: TextureWindow {
+                        x_mask: 0,
+                        y_mask: 0,
+                        x_offset: 0,
+                        y_offset: 0,
+                    },
+                    raw: true,
+                    replacement: None,
+                };
+
+                let mut frame = Frame::new(256, 256);
+
+                for v in 0..frame.height {
+                    for u in 0..frame.width {
+                        let texel = texture.fetch(&*vram, u as u8, v as u8);
+
+                        frame.set_pixel(u, v, rgb_from_15bit(texel));
+
+                        // Texel 0 is transparent when drawing.
+                        if texel == 0 {
+                            frame.set_alpha(u, v, 0);
+                        }
+                    }
+                }
+
+                frame
+            },
+        }
     }
 
-    pub fn read(&self) -> u32 {
-        0
+    /// Number of vblanks since power on.
+    pub fn frame(&self) -> u32 {
+        self.frame
     }
 
-    fn gp1_display_enable(&mut self, value: u32) {
-        self.display_disable = value & 1 != 0;
+    /// Fetches the RGB colour of a displayed pixel from VRAM. `sub_pixel`
+    /// selects a pixel within the upscaled block of the native one. 24bit
+    /// images are only ever uploaded by the CPU so they're always shown at
+    /// the native resolution.
+    fn display_pixel(&self,
+                     vram: &VRam,
+                     column: u16,
+                     vram_y: u16,
+                     (sub_x, sub_y): (u16, u16)) -> [u8; 3] {
+        let x_start = self.display_vram_x_start;
+
+        match self.display_depth {
+            DisplayDepth::D15Bits => {
+                let x = x_start.wrapping_add(column);
+
+                let pixel = match vram.scale() {
+                    1 => vram.load16(x, vram_y),
+                    scale => {
+                        let (x, y) = ((x & 0x3ff) * scale + sub_x, (vram_y & 0x1ff) * scale + sub_y);
+
+                        vram.load_upscaled(x, y)
+                    },
+                };
+
+                rgb_from_15bit(pixel)
+            },
+            DisplayDepth::D24Bits => pixel_24bit(vram, x_start, column, vram_y),
+        }
     }
 
-    fn gp1_display_mode(&mut self, value: u32) {
-        let hr1 = (value & 3) as u8;
-        let hr2 = ((value >> 6) & 1) as u8;
+    /// Records the GPU command stream to `path` for `frames` frames (or
+    /// until exit if `None`), starting at the vblank ending frame
+    /// `start_frame`.
+    pub fn record_to(&mut self, path: PathBuf, start_frame: u32, frames: Option<u32>) {
+        self.record_request = Some(RecordRequest {
+            path,
+            start_frame,
+            frames,
+        });
+
+        if start_frame <= self.frame {
+            self.start_recording();
+        }
+    }
 
-        self.hres = HorisontalRes::from_fields(hr1, hr2);
+    /// Starts a pending recording if the command decoder sits between two
+    /// commands and nothing is being drawn, since the dump can hold neither
+    /// a partially received command nor the work left on one.
+    fn start_recording(&mut self) {
+        let idle = self.gp0_mode == Gp0Mode::Command
+            && self.gp0_command_remaining == 0
+            && self.gp0_fifo.is_empty()
+            && self.busy_cycles == 0;
+
+        if !idle {
+            return;
+        }
 
-        self.vres = match value & 0x4 != 0 {
-            false => VerticalRes::Y240Lines,
-            true => VerticalRes::Y480Lines,
+        let request = match self.record_request.take() {
+            Some(r) => r,
+            None => return,
         };
 
-        self.vmode = match value & 0x8 != 0 {
-            false => VMode::NTSC,
-            true => VMode::PAL,
-        };
+        let state = self.state_commands();
 
-        self.display_depth = match value & 0x10 != 0 {
-            false => DisplayDepth::D24Bits,
-            true => DisplayDepth::D15Bits,
-        };
+        self.renderer.sync();
 
-        self.interlaced = value & 0x20 != 0;
+        let created = Recorder::create(&request.path,
+                                       self.renderer.vram().pixels(),
+                                       &self.timing_state(),
+                                       &state,
+                                       request.frames);
 
-        if value & 0x80 != 0 {
-            panic!("Unsupported display mode {:08x}", value);
+        match created {
+            Ok(recorder) => {
+                println!("Recording GPU commands to {}", request.path.display());
+                self.recorder = Some(recorder);
+            },
+            Err(e) => println!("Can't create GPU dump {}: {}", request.path.display(), e),
         }
     }
 
-    fn gp1_dma_direction(&mut self, value: u32) {
-        self.dma_direction = match value & 3 {
-            0 => DmaDirection::Off,
-            1 => DmaDirection::Fifo,
-            2 => DmaDirection::CpuToGpu0,
-            3 => DmaDirection::VRamToCpu,
-            _ => unreachable!(),
+    fn record(&mut self, event: Event, value: u32) {
+        if let Some(ref mut recorder) = self.recorder {
+            if let Err(e) = recorder.record(event, value) {
+                self.recording_failed(e);
+            }
         }
     }
 
-    fn gp1_display_vram_start(&mut self, value: u32) {
-        self.display_vram_x_start = (value & 0x3fe) as u16;
-        self.display_vram_y_start = ((value >> 10) & 0x1ff) as u16;
+    fn recording_failed(&mut self, e: io::Error) {
+        println!("GPU recording failed: {}", e);
+        self.recorder = None;
     }
 
-    fn gp1_display_horizontal_range(&mut self, value: u32) {
-        self.display_horizontal_start = (value & 0xfff) as u16;
-        self.display_horizontal_end = ((value >> 12) & 0xfff) as u16;
+    fn frame_end(&mut self) {
+        if self.debug_view.is_some() {
+            self.write_debug_view();
+        }
+
+        let drawn = self.renderer.take_stats();
+
+        self.stats.current_mut().add_draw(drawn);
+        self.stats.end_frame(self.frame);
+
+        self.frame += 1;
+
+        if let Some(ref mut recorder) = self.recorder {
+            match recorder.end_frame() {
+                Ok(true) => (),
+                Ok(false) => {
+                    println!("GPU recording done");
+                    self.recorder = None;
+                },
+                Err(e) => self.recording_failed(e),
+            }
+        }
     }
 
-    fn gp1_display_vertical_range(&mut self, value: u32) {
-        self.display_line_start = (value & 0x3ff) as u16;
-        self.display_line_end = ((value >> 10) & 0x3ff) as u16;
+    /// Dumps the textures sampled by primitives to `dir` and/or draws the
+    /// replacement images found there instead. Replacements with a higher
+    /// resolution than the original show with `set_internal_resolution`.
+    pub fn set_texture_pack(&mut self, dir: PathBuf, dump: bool, replace: bool) {
+        self.renderer.set_texture_pack(Some(TexturePack::new(dir, dump, replace)));
+    }
+
+    /// Writes diagnostic images and a primitive listing to `dir` at the end
+    /// of every frame.
+    pub fn set_debug_output(&mut self, dir: PathBuf) {
+        match DebugView::new(dir.clone()) {
+            Ok(view) => self.debug_view = Some(view),
+            Err(e) => println!("Can't create debug directory {}: {}", dir.display(), e),
+        }
+    }
+
+    fn write_debug_view(&mut self) {
+        self.renderer.sync();
+
+        let (width, _) = self.hres.dimensions();
+
+        let height = match self.vres {
+            VerticalRes::Y240Lines => 240,
+            VerticalRes::Y480Lines => 480,
+        };
+
+        let display = DrawArea {
+            left: self.display_vram_x_start as i32,
+            top: self.display_vram_y_start as i32,
+            right: self.display_vram_x_start as i32 + width as i32 - 1,
+            bottom: self.display_vram_y_start as i32 + height - 1,
+        };
+
+        let vram = self.renderer.vram();
+
+        if let Some(ref mut view) = self.debug_view {
+            if let Err(e) = view.end_frame(self.frame, &vram, display) {
+                println!("Can't write debug view: {}", e);
+            }
+        }
+    }
+
+    /// Hands `command` over to the renderer. Returns the estimated work the
+    /// drawing time is based on.
+    fn draw(&mut self, command: Command) -> DrawStats {
+        if let Some(ref mut view) = self.debug_view {
+            view.push(&command);
+        }
+
+        let counters = self.stats.current_mut();
+
+        match command {
+            Command::Triangle(..) => counters.triangles += 1,
+            Command::Quad(..) => counters.quads += 1,
+            Command::Rectangle { .. } => counters.rectangles += 1,
+            Command::Line(..) => counters.lines += 1,
+            Command::Fill { size: (width, height), .. } => {
+                counters.fills += 1;
+                counters.pixels += width as u64 * height as u64;
+            },
+            Command::Copy { size: (width, height), .. } => {
+                counters.copies += 1;
+                counters.pixels += width as u64 * height as u64;
+            },
+            Command::Store { .. } | Command::ClearCache => (),
+        }
+
+        self.renderer.draw(command)
+    }
+
+    /// Work done during the last complete frame.
+    pub fn frame_stats(&self) -> &FrameStats {
+        self.stats.last()
+    }
+
+    /// Totals, average and busiest frame since power on.
+    pub fn stats_report(&self) -> String {
+        self.stats.report()
+    }
+
+    /// Called by the DMA for every linked list packet it walks.
+    pub fn count_dma_packet(&mut self) {
+        self.stats.current_mut().dma_packets += 1;
+    }
+
+    /// GP1 and GP0 commands that bring a reset GPU to the current state.
+    fn state_commands(&self) -> Vec<(Event, u32)> {
+        let hr = self.hres.0 as u32;
+
+        let display_mode = (hr >> 1)
+            | ((self.vres as u32) << 2)
+            | ((self.vmode as u32) << 3)
+            | ((self.display_depth as u32) << 4)
+            | ((self.interlaced as u32) << 5)
+            | ((hr & 1) << 6);
+
+        let draw_mode = (self.page_base_x as u32)
+            | ((self.page_base_y as u32) << 4)
+            | ((self.semi_transparency as u32) << 5)
+            | ((self.texture_depth as u32) << 7)
+            | ((self.dithering as u32) << 9)
+            | ((self.draw_to_display as u32) << 10)
+            | ((self.textrure_disable as u32) << 11)
+            | ((self.rectangle_texture_x_flip as u32) << 12)
+            | ((self.rectangle_texture_y_flip as u32) << 13);
+
+        let texture_window = (self.texture_window_x_mask as u32)
+            | ((self.texture_window_y_mask as u32) << 5)
+            | ((self.texture_window_x_offset as u32) << 10)
+            | ((self.texture_window_y_offset as u32) << 15);
+
+        let offset_x = (self.drawing_x_offset as u32) & 0x7ff;
+        let offset_y = (self.drawing_y_offset as u32) & 0x7ff;
+
+        let mut commands = vec![
+            (Event::Gp1, 0x03000000 | self.display_disable as u32),
+            (Event::Gp1, 0x04000000 | self.dma_direction as u32),
+            (Event::Gp1, 0x05000000
+                | self.display_vram_x_start as u32
+                | ((self.display_vram_y_start as u32) << 10)),
+            (Event::Gp1, 0x06000000
+                | self.display_horizontal_start as u32
+                | ((self.display_horizontal_end as u32) << 12)),
+            (Event::Gp1, 0x07000000
+                | self.display_line_start as u32
+                | ((self.display_line_end as u32) << 10)),
+            (Event::Gp1, 0x08000000 | display_mode),
+            (Event::Gp1, 0x09000000 | self.texture_disable_allowed as u32),
+            (Event::Gp0, 0xe1000000 | draw_mode),
+            (Event::Gp0, 0xe2000000 | texture_window),
+            (Event::Gp0, 0xe3000000
+                | self.drawing_area_left as u32
+                | ((self.drawing_area_top as u32) << 10)),
+            (Event::Gp0, 0xe4000000
+                | self.drawing_area_right as u32
+                | ((self.drawing_area_bottom as u32) << 10)),
+            (Event::Gp0, 0xe5000000 | offset_x | (offset_y << 11)),
+            (Event::Gp0, 0xe6000000
+                | self.force_set_mask_bit as u32
+                | ((self.preserve_masked_pixels as u32) << 1)),
+        ];
+
+        if self.interrupt {
+            commands.push((Event::Gp0, 0x1f000000));
+        }
+
+        commands
+    }
+
+    pub fn timing_state(&self) -> TimingState {
+        TimingState {
+            line: self.line,
+            line_cycles: self.line_cycles,
+            clock_fraction: self.clock_fraction,
+            dot_fraction: self.dot_fraction,
+            top_field: self.field == Field::Top,
+            in_vblank: self.in_vblank,
+            busy_cycles: self.busy_cycles,
+        }
+    }
+
+    /// Restores the video clock from a snapshot.
+    pub fn set_timing_state(&mut self, state: &TimingState) {
+        self.line = state.line;
+        self.line_cycles = state.line_cycles;
+        self.clock_fraction = state.clock_fraction;
+        self.dot_fraction = state.dot_fraction;
+        self.in_vblank = state.in_vblank;
+        self.busy_cycles = state.busy_cycles;
+
+        self.field = match state.top_field {
+            true => Field::Top,
+            false => Field::Bottom,
+        };
+    }
+
+    /// Overwrites the whole VRAM, used to restore a snapshot.
+    pub fn load_vram(&mut self, pixels: &[u16]) {
+        self.renderer.sync();
+
+        let mut vram = self.renderer.vram();
+
+        vram.pixels_mut().copy_from_slice(pixels);
+
+        let scale = vram.scale();
+
+        // Rebuild the upscaled copy from the new contents.
+        vram.set_scale(scale);
+    }
+
+    /// Renders primitives at `scale` times the native resolution, 1, 2, 4
+    /// or 8.
+    pub fn set_internal_resolution(&mut self, scale: u16) {
+        if !matches!(scale, 1 | 2 | 4 | 8) {
+            panic!("Unsupported internal resolution {}x", scale);
+        }
+
+        self.renderer.sync();
+        self.renderer.vram().set_scale(scale);
+    }
+
+    /// Moves drawing to a worker thread. Drawing time is estimated from the
+    /// primitives' geometry either way, so it doesn't change the timing.
+    pub fn set_threaded_rendering(&mut self, threaded: bool) {
+        self.renderer.set_threaded(threaded);
+    }
+
+    /// Draws on the worker thread with the tile-parallel rasterizer split
+    /// across `threads` threads.
+    pub fn set_tile_threads(&mut self, threads: usize) {
+        self.renderer.set_tile_threads(threads);
+        self.renderer.set_threaded(true);
+    }
+
+    pub fn set_deinterlacing(&mut self, deinterlacing: Deinterlacing) {
+        self.deinterlacing = deinterlacing;
+    }
+
+    pub fn set_dithering_allowed(&mut self, allowed: bool) {
+        self.dithering_allowed = allowed;
+    }
+
+    /// Emulates the texture and CLUT caches, for games that rely on stale
+    /// texels after writing to VRAM. Off by default as it prevents
+    /// tile-parallel drawing.
+    pub fn set_texture_cache(&mut self, enabled: bool) {
+        self.texture_cache = enabled;
+        self.renderer.set_texture_cache(enabled);
+    }
+
+    /// Queues a word in the GP0 FIFO. Words written while it's full are
+    /// lost, like on the real hardware.
+    pub fn gp0(&mut self, value: u32) {
+        self.record(Event::Gp0, value);
+
+        self.gp0_push(value);
+    }
+
+    /// GP0 write coming from the DMA controller.
+    pub fn gp0_dma(&mut self, value: u32) {
+        self.record(Event::Gp0Dma, value);
+
+        self.gp0_push(value);
+    }
+
+    fn gp0_push(&mut self, value: u32) {
+        if self.gp0_fifo.is_full() {
+            println!("GP0 FIFO overflow, dropping {:08x}", value);
+            return;
+        }
+
+        self.gp0_fifo.push(value);
+
+        self.run_fifo();
+    }
+
+    pub fn gp0_fifo_full(&self) -> bool {
+        self.gp0_fifo.is_full()
+    }
+
+    /// CPU cycles until the GPU is done with the current command.
+    pub fn busy_cpu_cycles(&self) -> u32 {
+        (self.busy_cycles * 7).div_ceil(11).max(1)
+    }
+
+    /// Feeds words from the FIFO to the command decoder until it's empty or
+    /// the GPU is busy drawing.
+    fn run_fifo(&mut self) {
+        while self.busy_cycles == 0 {
+            match self.gp0_fifo.pop() {
+                Some(value) => self.gp0_execute(value),
+                None => break,
+            }
+        }
+    }
+
+    fn gp0_execute(&mut self, value: u32) {
+        if self.gp0_mode == Gp0Mode::PolyLine {
+            return self.polyline_word(value);
+        }
+
+        if self.gp0_command_remaining == 0 {
+            let opcode = (value >> 24) & 0xff;
+
+            let (length, method) = match opcode {
+                0x00 => (1, Gpu::gp0_nop as fn(&mut Gpu)),
+                0x01 => (1, Gpu::gp0_clear_cache as fn(&mut Gpu)),
+                0x02 => (3, Gpu::gp0_fill_rect as fn(&mut Gpu)),
+                0x04..=0x1e => (1, Gpu::gp0_nop as fn(&mut Gpu)),
+                0x1f => (1, Gpu::gp0_interrupt_request as fn(&mut Gpu)),
+                0x20..=0x3f => (polygon_length(opcode), Gpu::gp0_polygon as fn(&mut Gpu)),
+                0x40..=0x5f => (3 + ((opcode >> 4) & 1), Gpu::gp0_line as fn(&mut Gpu)),
+                0x60..=0x7f => (rectangle_length(opcode), Gpu::gp0_rectangle as fn(&mut Gpu)),
+                0x80..=0x9f => (4, Gpu::gp0_vram_copy as fn(&mut Gpu)),
+                0xa0..=0xbf => (3, Gpu::gp0_image_load as fn(&mut Gpu)),
+                0xc0..=0xdf => (3, Gpu::gp0_image_store as fn(&mut Gpu)),
+                0xe0 => (1, Gpu::gp0_nop as fn(&mut Gpu)),
+                0xe1 => (1, Gpu::gp0_draw_mode as fn(&mut Gpu)),
+                0xe2 => (1, Gpu::gp0_texture_window as fn(&mut Gpu)),
+                0xe3 => (1, Gpu::gp0_drawing_area_top_left as fn(&mut Gpu)),
+                0xe4 => (1, Gpu::gp0_drawing_area_bottom_right as fn(&mut Gpu)),
+                0xe5 => (1, Gpu::gp0_drawing_offset as fn(&mut Gpu)),
+                0xe6 => (1, Gpu::gp0_mask_bit_setting as fn(&mut Gpu)),
+                0xe7..=0xef => (1, Gpu::gp0_nop as fn(&mut Gpu)),
+                // 0x03 and 0xf0-0xff aren't documented, the GPU consumes
+                // them as single word no-ops.
+                _ => (1, Gpu::gp0_unknown as fn(&mut Gpu)),
+            };
+
+            self.gp0_command_remaining = length;
+            self.gp0_command_method = method;
+
+            self.gp0_command.clear();
+        }
+        
+        self.gp0_command_remaining -= 1;
+
+        match self.gp0_mode {
+            Gp0Mode::Command => {
+                self.gp0_command.push_word(value);
+
+                if self.gp0_command_remaining == 0 {
+                    (self.gp0_command_method)(self);
+                }
+            },
+            Gp0Mode::ImageLoad => {
+                self.image_load_pixel(value as u16);
+
+                if self.image_load.remaining() > 0 {
+                    self.image_load_pixel((value >> 16) as u16);
+                }
+
+                if self.gp0_command_remaining == 0 {
+                    self.gp0_mode = Gp0Mode::Command;
+                }
+            },
+            Gp0Mode::PolyLine => unreachable!(),
+        }
+    }
+
+    pub fn gp1(&mut self, value: u32) {
+        self.record(Event::Gp1, value);
+
+        self.stats.current_mut().gp1_writes += 1;
+
+        // The opcode is only 6 bits wide, 0x40-0xff mirror 0x00-0x3f.
+        let opcode = (value >> 24) & 0x3f;
+
+        match opcode {
+            0x00 => self.gp1_reset(value),
+            0x01 => self.gp1_reset_command_buffer(),
+            0x02 => self.gp1_acknowledge_irq(),
+            0x03 => self.gp1_display_enable(value),
+            0x04 => self.gp1_dma_direction(value),
+            0x05 => self.gp1_display_vram_start(value),
+            0x06 => self.gp1_display_horizontal_range(value),
+            0x07 => self.gp1_display_vertical_range(value),
+            0x08 => self.gp1_display_mode(value),
+            0x09 => self.gp1_texture_disable(value),
+            0x10..=0x1f => self.gp1_get_info(value),
+            0x20 => self.gp1_texture_disable_extension(value),
+            _ => println!("Unhandled GPU1 command {:08x}", value),
+        }
+    }
+
+    fn gp0_nop(&mut self) {
+
+    }
+
+    fn gp0_unknown(&mut self) {
+        println!("Unhandled GPU0 command {:08x}", self.gp0_command[0]);
+    }
+
+    fn gp0_interrupt_request(&mut self) {
+        // IRQ1 is edge triggered, nothing new happens until GP1 0x02
+        // acknowledges it.
+        if !self.interrupt {
+            self.irq_pending = true;
+        }
+
+        self.interrupt = true;
+    }
+
+    /// Whether GP0 0x1F raised IRQ1 since the last call.
+    pub fn take_irq(&mut self) -> bool {
+        let pending = self.irq_pending;
+
+        self.irq_pending = false;
+
+        pending
+    }
+
+    fn gp0_clear_cache(&mut self) {
+        self.clear_texture_cache();
+    }
+
+    fn clear_texture_cache(&mut self) {
+        if self.texture_cache {
+            self.renderer.draw(Command::ClearCache);
+        }
+    }
+
+    /// Fills a rectangle with a flat colour. Unlike every other draw command
+    /// it ignores the drawing area, the drawing offset and the mask settings.
+    /// It does leave the displayed field alone in 480 line interlaced mode.
+    fn gp0_fill_rect(&mut self) {
+        let color = Color::from_command(self.gp0_command[0]).to_15bit();
+
+        let position = self.gp0_command[1];
+        let size = self.gp0_command[2];
+
+        let x = (position & 0x3f0) as u16;
+        let y = ((position >> 16) & 0x1ff) as u16;
+
+        // Widths are rounded up to a multiple of 16 pixels.
+        let width = (((size & 0x3ff) + 0xf) & !0xf) as u16;
+        let height = ((size >> 16) & 0x1ff) as u16;
+
+        self.draw(Command::Fill {
+            position: (x, y),
+            size: (width, height),
+            color,
+            skipped_lines: self.skipped_lines(),
+        });
+
+        self.busy_cycles += 46 + (width as u32 / 8 + 9) * height as u32;
+    }
+
+    fn gp0_vram_copy(&mut self) {
+        let src = ImageTransfer::from_command(self.gp0_command[1], self.gp0_command[3]);
+        let dst = ImageTransfer::from_command(self.gp0_command[2], self.gp0_command[3]);
+
+        self.draw(Command::Copy {
+            src: (src.x, src.y),
+            dst: (dst.x, dst.y),
+            size: (src.width, src.height),
+            mask: self.mask_settings(),
+        });
+
+        self.busy_cycles += src.width as u32 * src.height as u32 * 2;
+    }
+
+    fn gp0_image_store(&mut self) {
+        self.image_store = ImageTransfer::from_command(self.gp0_command[1], self.gp0_command[2]);
+
+        let counters = self.stats.current_mut();
+
+        counters.downloads += 1;
+        counters.download_pixels += self.image_store.remaining() as u64;
+    }
+
+    fn gp0_image_load(&mut self) {
+        self.image_load = ImageTransfer::from_command(self.gp0_command[1], self.gp0_command[2]);
+
+        let image_size = self.image_load.remaining();
+
+        let counters = self.stats.current_mut();
+
+        counters.uploads += 1;
+        counters.upload_pixels += image_size as u64;
+
+        let image_size = (image_size + 1) & !1;
+
+        self.gp0_command_remaining = image_size / 2;
+        self.gp0_mode = Gp0Mode::ImageLoad;
+    }
+
+    fn image_load_pixel(&mut self, pixel: u16) {
+        let position = self.image_load.next_position();
+
+        if self.upload_row.is_empty() {
+            self.upload_row_position = position;
+        }
+
+        self.upload_row.push(pixel);
+
+        if self.image_load.row_complete() {
+            self.flush_upload_row();
+        }
+    }
+
+    /// Sends the pixels of the current upload row to the renderer.
+    fn flush_upload_row(&mut self) {
+        if self.upload_row.is_empty() {
+            return;
+        }
+
+        let pixels = mem::replace(&mut self.upload_row, Vec::with_capacity(self.image_load.width as usize));
+
+        self.draw(Command::Store {
+            position: self.upload_row_position,
+            pixels,
+            mask: self.mask_settings(),
+        });
+    }
+
+    /// GP0 0xE6 bits, for the VRAM writes that don't go through the
+    /// rasterizer.
+    fn mask_settings(&self) -> MaskSettings {
+        MaskSettings {
+            set_mask: self.force_set_mask_bit,
+            check_mask: self.preserve_masked_pixels,
+        }
+    }
+
+    /// GP0 0x20-0x3F. The low opcode bits select shading, vertex count and
+    /// texturing.
+    fn gp0_polygon(&mut self) {
+        let opcode = self.gp0_command[0] >> 24;
+
+        let shaded = opcode & 0x10 != 0;
+        let quad = opcode & 0x08 != 0;
+        let textured = opcode & 0x04 != 0;
+        let semi_transparent = opcode & 0x02 != 0;
+        let raw = opcode & 0x01 != 0;
+
+        let vertex_count = if quad { 4 } else { 3 };
+
+        let mut clut = 0;
+
+        let mut vertices = [Vertex::from_command(0, Color::from_command(0), 0, 0); 4];
+
+        let mut color = Color::from_command(self.gp0_command[0]);
+        let mut index = 1;
+
+        for (i, vertex) in vertices.iter_mut().enumerate().take(vertex_count) {
+            if shaded && i > 0 {
+                color = Color::from_command(self.gp0_command[index]);
+                index += 1;
+            }
+
+            *vertex = Vertex::from_command(self.gp0_command[index],
+                                           color,
+                                           self.drawing_x_offset,
+                                           self.drawing_y_offset);
+            index += 1;
+
+            if textured {
+                let texcoord = self.gp0_command[index];
+
+                vertex.set_texcoord(texcoord);
+
+                match i {
+                    0 => clut = texcoord >> 16,
+                    1 => self.set_texture_page(texcoord >> 16),
+                    _ => (),
+                }
+
+                index += 1;
+            }
+        }
+
+        let texture = match textured && !self.textrure_disable {
+            true => Some(self.texture(clut, raw)),
+            false => None,
+        };
+
+        // Textured polygons blend with the mode from their own texpage
+        // attribute, which has already been applied above.
+        let dither = shaded || (textured && !raw);
+
+        let state = self.draw_state(semi_transparent, dither);
+
+        let command = if quad {
+            Command::Quad(state, vertices, texture)
+        } else {
+            Command::Triangle(state, [vertices[0], vertices[1], vertices[2]], texture)
+        };
+
+        let stats = self.draw(command);
+
+        let triangles = if quad { 2 } else { 1 };
+
+        self.add_draw_time(&state, stats, triangles * POLYGON_SETUP_CYCLES);
+    }
+
+    /// GP0 0x40-0x5F. Polylines only buffer their first segment here, the
+    /// following vertices are streamed through `polyline_word`.
+    fn gp0_line(&mut self) {
+        let opcode = self.gp0_command[0] >> 24;
+
+        let shaded = opcode & 0x10 != 0;
+        let poly = opcode & 0x08 != 0;
+        let semi_transparent = opcode & 0x02 != 0;
+
+        let color = Color::from_command(self.gp0_command[0]);
+
+        let start = Vertex::from_command(self.gp0_command[1],
+                                         color,
+                                         self.drawing_x_offset,
+                                         self.drawing_y_offset);
+
+        let (color, end) = match shaded {
+            true => (Color::from_command(self.gp0_command[2]), self.gp0_command[3]),
+            false => (color, self.gp0_command[2]),
+        };
+
+        let end = Vertex::from_command(end, color, self.drawing_x_offset, self.drawing_y_offset);
+
+        self.polyline = PolyLine {
+            last: end,
+            color,
+            shaded,
+            semi_transparent,
+            have_color: false,
+        };
+
+        self.draw_line(start, end);
+
+        if poly {
+            self.gp0_mode = Gp0Mode::PolyLine;
+        }
+    }
+
+    fn polyline_word(&mut self, value: u32) {
+        let vertex_start = !self.polyline.shaded || !self.polyline.have_color;
+
+        // The terminator only needs to match 0x5xxx5xxx and is only looked
+        // for where a new vertex would begin.
+        if vertex_start && value & 0xf000f000 == 0x50005000 {
+            self.gp0_mode = Gp0Mode::Command;
+            return;
+        }
+
+        if self.polyline.shaded && !self.polyline.have_color {
+            self.polyline.color = Color::from_command(value);
+            self.polyline.have_color = true;
+            return;
+        }
+
+        let end = Vertex::from_command(value,
+                                       self.polyline.color,
+                                       self.drawing_x_offset,
+                                       self.drawing_y_offset);
+
+        let start = self.polyline.last;
+
+        self.polyline.last = end;
+        self.polyline.have_color = false;
+
+        self.draw_line(start, end);
+    }
+
+    fn draw_line(&mut self, start: Vertex, end: Vertex) {
+        let state = self.draw_state(self.polyline.semi_transparent, self.polyline.shaded);
+
+        let stats = self.draw(Command::Line(state, start, end));
+
+        self.add_draw_time(&state, stats, LINE_SETUP_CYCLES);
+    }
+
+    /// GP0 0x60-0x7F. Bits 3-4 of the opcode select a variable size or one
+    /// of the fixed 1x1, 8x8 and 16x16 sizes.
+    fn gp0_rectangle(&mut self) {
+        let opcode = self.gp0_command[0] >> 24;
+
+        let textured = opcode & 0x04 != 0;
+        let semi_transparent = opcode & 0x02 != 0;
+        let raw = opcode & 0x01 != 0;
+
+        let color = Color::from_command(self.gp0_command[0]);
+
+        let mut vertex = Vertex::from_command(self.gp0_command[1],
+                                              color,
+                                              self.drawing_x_offset,
+                                              self.drawing_y_offset);
+
+        let mut index = 2;
+
+        let mut clut = 0;
+
+        if textured {
+            let texcoord = self.gp0_command[index];
+
+            vertex.set_texcoord(texcoord);
+            clut = texcoord >> 16;
+
+            index += 1;
+        }
+
+        // Rectangles use the current texpage, only the CLUT comes with the
+        // command.
+        let texture = match textured && !self.textrure_disable {
+            true => Some(self.texture(clut, raw)),
+            false => None,
+        };
+
+        let (width, height) = match (opcode >> 3) & 3 {
+            0 => {
+                let size = self.gp0_command[index];
+
+                ((size & 0x3ff) as i32, ((size >> 16) & 0x1ff) as i32)
+            },
+            1 => (1, 1),
+            2 => (8, 8),
+            _ => (16, 16),
+        };
+
+        // Rectangles are never dithered.
+        let state = self.draw_state(semi_transparent, false);
+
+        let flip = (self.rectangle_texture_x_flip, self.rectangle_texture_y_flip);
+
+        let stats = self.draw(Command::Rectangle {
+            state,
+            vertex,
+            size: (width, height),
+            texture,
+            flip,
+        });
+
+        self.add_draw_time(&state, stats, RECTANGLE_SETUP_CYCLES);
+    }
+
+    /// Charges the time the GPU takes to draw a primitive: a fixed setup
+    /// cost, then every pixel and texel fetch, and the background read when
+    /// blending.
+    fn add_draw_time(&mut self, state: &DrawState, stats: DrawStats, setup: u32) {
+        let mut cycles = setup + stats.pixels + stats.texels;
+
+        if state.semi_transparency.is_some() || state.check_mask {
+            cycles += stats.pixels;
+        }
+
+        self.busy_cycles += cycles;
+    }
+
+    /// Builds the texture state for a primitive from its CLUT attribute and
+    /// the current texpage and texture window.
+    fn texture(&self, clut: u32, raw: bool) -> Texture {
+        let (clut_x, clut_y) = Texture::clut_from_attribute(clut);
+
+        Texture {
+            page_x: self.page_base_x as u16 * 64,
+            page_y: self.page_base_y as u16 * 256,
+            depth: self.texture_depth,
+            clut_x,
+            clut_y,
+            window: TextureWindow {
+                x_mask: self.texture_window_x_mask,
+                y_mask: self.texture_window_y_mask,
+                x_offset: self.texture_window_x_offset,
+                y_offset: self.texture_window_y_offset,
+            },
+            raw,
+            replacement: None,
+        }
+    }
+
+    /// Texpage bits shared by GP0 0xE1 and the polygon texpage attribute.
+    fn set_texture_page(&mut self, value: u32) {
+        let page = (self.page_base_x, self.page_base_y, self.texture_depth);
+
+        self.page_base_x = (value & 0xf) as u8;
+        self.page_base_y = ((value >> 4) & 1) as u8;
+
+        self.semi_transparency = ((value >> 5) & 3) as u8;
+
+        self.texture_depth = TextureDepth::from_field(value >> 7);
+
+        // Cached texels belong to the previous page.
+        if page != (self.page_base_x, self.page_base_y, self.texture_depth) {
+            self.clear_texture_cache();
+        }
+    }
+
+    /// `dither` tells whether the primitive is of a kind the GPU dithers,
+    /// that is Gouraud shaded or texture blended.
+    fn draw_state(&self, semi_transparent: bool, dither: bool) -> DrawState {
+        let semi_transparency = match semi_transparent {
+            true => Some(SemiTransparency::from_field(self.semi_transparency)),
+            false => None,
+        };
+
+        DrawState {
+            area: DrawArea {
+                left: self.drawing_area_left as i32,
+                top: self.drawing_area_top as i32,
+                right: self.drawing_area_right as i32,
+                bottom: self.drawing_area_bottom as i32,
+            },
+            semi_transparency,
+            set_mask: self.force_set_mask_bit,
+            check_mask: self.preserve_masked_pixels,
+            dither: dither && self.dithering && self.dithering_allowed,
+            scale: 1,
+            skipped_lines: self.skipped_lines(),
+        }
+    }
+
+    /// In 480 line interlaced mode the GPU only draws the lines of the
+    /// field that isn't being displayed, unless GP0 0xE1 allows drawing to
+    /// the display.
+    fn skipped_lines(&self) -> Option<i32> {
+        let interlaced_480 = self.interlaced && matches!(self.vres, VerticalRes::Y480Lines);
+
+        match interlaced_480 && !self.draw_to_display {
+            true => Some((self.display_vram_y_start as i32 + 1 - self.drawn_field()) & 1),
+            false => None,
+        }
+    }
+
+    /// Parity of the display lines drawn during the current field in 480
+    /// line interlaced mode, the other field is being displayed.
+    fn drawn_field(&self) -> i32 {
+        (self.field == Field::Bottom) as i32
+    }
+
+    fn gp0_draw_mode(&mut self) {
+        let value = self.gp0_command[0];
+
+        self.set_texture_page(value);
+
+        self.dithering = ((value >> 9) & 1) != 0;
+        self.draw_to_display = ((value >> 10) & 1) != 0;
+        self.textrure_disable = self.texture_disable_allowed && ((value >> 11) & 1) != 0;
+        self.rectangle_texture_x_flip = ((value >> 12) & 1) != 0;
+        self.rectangle_texture_y_flip = ((value >> 13) & 1) != 0;
+    }
+
+    fn gp0_texture_window(&mut self) {
+        let value = self.gp0_command[0];
+
+        self.texture_window_x_mask = (value & 0x1f) as u8;
+        self.texture_window_y_mask = ((value >> 5) & 0x1f) as u8;
+        self.texture_window_x_offset = ((value >> 10) & 0x1f) as u8;
+        self.texture_window_y_offset = ((value >> 15) & 0x1f) as u8;
+    }
+
+    fn gp0_drawing_area_top_left(&mut self) {
+        let value = self.gp0_command[0];
+
+        // The Y coordinates are 10 bits wide but VRAM is only 512 lines
+        // high, the top bit is dropped like when drawing.
+        self.drawing_area_left = (value & 0x3ff) as u16;
+        self.drawing_area_top = ((value  >> 10)& 0x1ff) as u16;
+    }
+
+    fn gp0_drawing_area_bottom_right(&mut self) {
+        let value = self.gp0_command[0];
+
+        self.drawing_area_right = (value & 0x3ff) as u16;
+        self.drawing_area_bottom = ((value  >> 10)& 0x1ff) as u16;
+    }
+
+    fn gp0_drawing_offset(&mut self) {
+        let value = self.gp0_command[0];
+
+        let x = (value & 0x7ff) as u16;
+        let y = ((value  >> 11)& 0x7ff) as u16;
+
+        self.drawing_x_offset = ((x << 5) as i16) >> 5;
+        self.drawing_y_offset = ((y << 5) as i16) >> 5;
+    }
+
+    fn gp0_mask_bit_setting(&mut self) {
+        let value = self.gp0_command[0];
+
+        self.force_set_mask_bit = (value & 1) != 0;
+        self.preserve_masked_pixels = (value & 2) != 0;
+    }
+
+    fn gp1_reset(&mut self, value: u32) {
+            self.gp1_reset_command_buffer();
+
+            self.page_base_x = 0;
+            self.page_base_y = 0;
+
+            self.semi_transparency = 0;
+
+            self.texture_depth = TextureDepth::T4Bit;
+            
+            self.dithering = false;
+
+            self.draw_to_display = false;
+
+            self.force_set_mask_bit = false;
+
+            self.preserve_masked_pixels = false;
+
+            self.field = Field::Top;
+
+            self.textrure_disable = false;
+
+            self.hres = HorisontalRes::from_fields(0, 0);
+            self.vres = VerticalRes::Y240Lines;
+
+            self.vmode = VMode::NTSC;
+
+            self.display_depth = DisplayDepth::D15Bits;
+
+            self.interlaced = false;
+
+            self.display_disable = true;
+
+            self.interrupt = false;
+            self.irq_pending = false;
+
+            self.dma_direction = DmaDirection::Off;
+
+            self.rectangle_texture_x_flip = false;
+            self.rectangle_texture_y_flip = false;
+
+            self.texture_window_x_mask = 0;
+            self.texture_window_y_mask = 0;
+
+            self.texture_window_x_offset = 0;
+            self.texture_window_y_offset = 0;
+
+            self.drawing_area_left = 0;
+            self.drawing_area_top = 0;
+            self.drawing_area_right = 0;
+            self.drawing_area_bottom = 0;
+
+            self.drawing_x_offset = 0;
+            self.drawing_y_offset = 0;
+
+            self.display_vram_x_start = 0;
+            self.display_vram_y_start = 0;
+
+            self.display_horizontal_start = 0x200;
+            self.display_horizontal_end = 0xc00;
+
+            self.display_line_start = 0x10;
+            self.display_line_end = 0x100;
+    }
+
+    /// GP1 0x09. Lets GP0 0xE1 bit 11 disable texturing.
+    fn gp1_texture_disable(&mut self, value: u32) {
+        self.texture_disable_allowed = value & 1 != 0;
+    }
+
+    /// GP1 0x20. Arcade boards enable texture disabling through this one
+    /// instead of GP1 0x09.
+    fn gp1_texture_disable_extension(&mut self, value: u32) {
+        match value & 0xffffff {
+            0x501 => self.texture_disable_allowed = true,
+            0x504 => self.texture_disable_allowed = false,
+            _ => println!("Unhandled GPU1 0x20 argument {:06x}", value & 0xffffff),
+        }
+    }
+
+    /// GP1 0x10. The answer is latched in GPUREAD, unknown requests leave
+    /// the previous value in place.
+    fn gp1_get_info(&mut self, value: u32) {
+        self.read_latch = match value & 0xf {
+            0x02 => {
+                (self.texture_window_x_mask as u32)
+                    | ((self.texture_window_y_mask as u32) << 5)
+                    | ((self.texture_window_x_offset as u32) << 10)
+                    | ((self.texture_window_y_offset as u32) << 15)
+            },
+            0x03 => (self.drawing_area_left as u32) | ((self.drawing_area_top as u32) << 10),
+            0x04 => (self.drawing_area_right as u32) | ((self.drawing_area_bottom as u32) << 10),
+            0x05 => {
+                let x = (self.drawing_x_offset as u32) & 0x7ff;
+                let y = (self.drawing_y_offset as u32) & 0x7ff;
+
+                x | (y << 11)
+            },
+            // GPU version, 2 for the 208 pin GPU found in most consoles.
+            0x07 => 2,
+            0x08 => 0,
+            _ => self.read_latch,
+        };
+    }
+
+    /// Drops the queued words along with the command or transfer they
+    /// belong to.
+    fn gp1_reset_command_buffer(&mut self) {
+        self.gp0_fifo.clear();
+        self.gp0_command.clear();
+        self.gp0_command_remaining = 0;
+        self.gp0_mode = Gp0Mode::Command;
+        self.polyline = PolyLine::new();
+        // What was uploaded so far is already in VRAM on the hardware.
+        self.flush_upload_row();
+        self.image_load = ImageTransfer::new();
+        self.image_store = ImageTransfer::new();
+        self.busy_cycles = 0;
+    }
+
+    fn gp1_acknowledge_irq(&mut self) {
+        self.interrupt = false;
+    }
+
+    /// GPUREAD. Streams the GP0 0xC0 rectangle two pixels at a time, once
+    /// it's exhausted the last value read is returned again.
+    pub fn read(&mut self) -> u32 {
+        if self.image_store.remaining() > 0 {
+            self.renderer.sync();
+
+            let vram = self.renderer.vram();
+
+            let (x, y) = self.image_store.next_position();
+            let low = vram.load16(x, y) as u32;
+
+            let high = match self.image_store.remaining() > 0 {
+                true => {
+                    let (x, y) = self.image_store.next_position();
+                    vram.load16(x, y) as u32
+                },
+                false => 0,
+            };
+
+            self.read_latch = low | (high << 16);
+        }
+
+        self.read_latch
+    }
+
+    fn gp1_display_enable(&mut self, value: u32) {
+        self.display_disable = value & 1 != 0;
+    }
+
+    fn gp1_display_mode(&mut self, value: u32) {
+        let hr1 = (value & 3) as u8;
+        let hr2 = ((value >> 6) & 1) as u8;
+
+        self.hres = HorisontalRes::from_fields(hr1, hr2);
+
+        self.vres = match value & 0x4 != 0 {
+            false => VerticalRes::Y240Lines,
+            true => VerticalRes::Y480Lines,
+        };
+
+        self.vmode = match value & 0x8 != 0 {
+            false => VMode::NTSC,
+            true => VMode::PAL,
+        };
+
+        self.display_depth = match value & 0x10 != 0 {
+            false => DisplayDepth::D15Bits,
+            true => DisplayDepth::D24Bits,
+        };
+
+        self.interlaced = value & 0x20 != 0;
+
+        if value & 0x80 != 0 {
+            panic!("Unsupported display mode {:08x}", value);
+        }
+    }
+
+    fn gp1_dma_direction(&mut self, value: u32) {
+        self.dma_direction = match value & 3 {
+            0 => DmaDirection::Off,
+            1 => DmaDirection::Fifo,
+            2 => DmaDirection::CpuToGpu0,
+            3 => DmaDirection::VRamToCpu,
+            _ => unreachable!(),
+        }
+    }
+
+    fn gp1_display_vram_start(&mut self, value: u32) {
+        self.display_vram_x_start = (value & 0x3fe) as u16;
+        self.display_vram_y_start = ((value >> 10) & 0x1ff) as u16;
+    }
+
+    fn gp1_display_horizontal_range(&mut self, value: u32) {
+        self.display_horizontal_start = (value & 0xfff) as u16;
+        self.display_horizontal_end = ((value >> 12) & 0xfff) as u16;
+    }
+
+    fn gp1_display_vertical_range(&mut self, value: u32) {
+        self.display_line_start = (value & 0x3ff) as u16;
+        self.display_line_end = ((value >> 10) & 0x3ff) as u16;
     }
 }
 
+#[derive(PartialEq, Eq)]
 enum Gp0Mode {
     Command,
     ImageLoad,
+    PolyLine,
 }
 
-#[derive(Clone, Copy)]
-enum TextureDepth {
-    T4Bit = 0,
-    T8Bit = 1,
-    T15Bit = 2,
+/// Polyline being streamed through GP0.
+struct PolyLine {
+    last: Vertex,
+    /// Colour of the next vertex.
+    color: Color,
+    shaded: bool,
+    semi_transparent: bool,
+    /// For shaded polylines, whether the colour word of the next vertex has
+    /// already been received.
+    have_color: bool,
 }
 
-#[derive(Clone, Copy)]
+impl PolyLine {
+    fn new() -> PolyLine {
+        PolyLine {
+            last: Vertex::from_command(0, Color::from_command(0), 0, 0),
+            color: Color::from_command(0),
+            shaded: false,
+            semi_transparent: false,
+            have_color: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Field {
     Top = 1,
     Bottom = 0,
@@ -525,26 +1866,284 @@ enum VMode {
     PAL = 1,
 }
 
-#[derive(Clone, Copy)]
-enum VerticalRes {
-    Y240Lines = 0,
-    Y480Lines = 1,
+#[derive(Clone, Copy)]
+enum VerticalRes {
+    Y240Lines = 0,
+    Y480Lines = 1,
+}
+
+#[derive(Clone, Copy)]
+struct HorisontalRes(u8);
+
+impl HorisontalRes {
+    fn from_fields(hr1: u8, hr2: u8) -> HorisontalRes {
+        let hr = (hr2 & 1) | ((hr1 & 3) << 1);
+        HorisontalRes(hr)
+    }
+
+    fn into_status(self) -> u32 {
+        let HorisontalRes(hr) = self;
+        (hr as u32) << 16
+    }
+
+    /// Horizontal resolution in pixels and the matching number of GPU
+    /// cycles per pixel.
+    fn dimensions(self) -> (u32, u32) {
+        let HorisontalRes(hr) = self;
+
+        if hr & 1 != 0 {
+            return (368, 7);
+        }
+
+        match hr >> 1 {
+            0 => (256, 10),
+            1 => (320, 8),
+            2 => (512, 5),
+            _ => (640, 4),
+        }
+    }
+}
+
+/// RGBA picture of the displayed frame.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// 4 bytes per pixel, row after row.
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: u32, height: u32) -> Frame {
+        let mut pixels = vec![0; (width * height * 4) as usize];
+
+        // Opaque black.
+        for alpha in pixels.iter_mut().skip(3).step_by(4) {
+            *alpha = 0xff;
+        }
+
+        Frame {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, [r, g, b]: [u8; 3]) {
+        let offset = ((y * self.width + x) * 4) as usize;
+
+        self.pixels[offset] = r;
+        self.pixels[offset + 1] = g;
+        self.pixels[offset + 2] = b;
+    }
+
+    fn set_alpha(&mut self, x: u32, y: u32, alpha: u8) {
+        let offset = ((y * self.width + x) * 4) as usize;
+
+        self.pixels[offset + 3] = alpha;
+    }
+
+    /// Writes the frame as PNG, or PPM if the file name ends with `.ppm`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        image::save(path, self.width, self.height, &self.pixels)
+    }
+}
+
+/// How `Gpu::display_frame` combines the two fields of a 480 line
+/// interlaced image.
+#[derive(Clone, Copy)]
+pub enum Deinterlacing {
+    /// Both fields as they are in VRAM, moving parts comb.
+    Weave,
+    /// The last complete field with its lines doubled.
+    Bob,
+    /// Each pair of lines averaged.
+    Blend,
+}
+
+impl Deinterlacing {
+    /// Display lines shown on `line` given the parity of the last complete
+    /// field.
+    fn lines(self, line: i32, field: i32) -> (i32, i32) {
+        match self {
+            Deinterlacing::Weave => (line, line),
+            Deinterlacing::Bob => ((line & !1) | field, (line & !1) | field),
+            Deinterlacing::Blend => (line & !1, line | 1),
+        }
+    }
+}
+
+/// What part of VRAM `Gpu::vram_frame` shows and how to decode it.
+#[derive(Clone, Copy)]
+pub enum VRamView {
+    Bits15,
+    Bits24,
+    /// A 256x256 texel page, decoded through the CLUT for 4 and 8bit
+    /// textures. Coordinates are in VRAM pixels.
+    Texture {
+        page_x: u16,
+        page_y: u16,
+        depth: TextureDepth,
+        clut_x: u16,
+        clut_y: u16,
+    },
+}
+
+/// Pixels are packed as 3 bytes straddling the 16bit words.
+fn pixel_24bit(vram: &VRam, x_start: u16, column: u16, y: u16) -> [u8; 3] {
+    let byte = |index: u16| {
+        let x = x_start.wrapping_add(index / 2);
+        let word = vram.load16(x, y);
+
+        (word >> ((index & 1) * 8)) as u8
+    };
+
+    let index = column * 3;
+
+    [byte(index), byte(index + 1), byte(index + 2)]
+}
+
+pub fn rgb_from_15bit(pixel: u16) -> [u8; 3] {
+    let expand = |c: u16| {
+        let c = (c & 0x1f) as u8;
+
+        (c << 3) | (c >> 2)
+    };
+
+    [expand(pixel), expand(pixel >> 5), expand(pixel >> 10)]
+}
+
+/// Number of words in a GP0 0x20-0x3F polygon command.
+fn polygon_length(opcode: u32) -> u32 {
+    let shaded = opcode & 0x10 != 0;
+    let quad = opcode & 0x08 != 0;
+    let textured = opcode & 0x04 != 0;
+
+    let vertices = if quad { 4 } else { 3 };
+
+    let vertex_words = 1 + textured as u32 + shaded as u32;
+
+    // The first vertex colour is part of the command word.
+    vertices * vertex_words + 1 - shaded as u32
+}
+
+/// Number of words in a GP0 0x60-0x7F rectangle command.
+fn rectangle_length(opcode: u32) -> u32 {
+    let textured = opcode & 0x04 != 0;
+    let variable_size = (opcode >> 3) & 3 == 0;
+
+    2 + textured as u32 + variable_size as u32
+}
+
+/// Rectangle being transferred between the CPU and VRAM.
+struct ImageTransfer {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+
+    index: u32,
+}
+
+impl ImageTransfer {
+    fn new() -> ImageTransfer {
+        ImageTransfer {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+
+            index: 0,
+        }
+    }
+
+    fn from_command(position: u32, size: u32) -> ImageTransfer {
+        let x = (position & 0x3ff) as u16;
+        let y = ((position >> 16) & 0x1ff) as u16;
+
+        // A size of 0 means the maximum, the GPU only keeps the low bits.
+        let width = (((size & 0xffff).wrapping_sub(1) & 0x3ff) + 1) as u16;
+        let height = (((size >> 16).wrapping_sub(1) & 0x1ff) + 1) as u16;
+
+        ImageTransfer {
+            x,
+            y,
+            width,
+            height,
+
+            index: 0,
+        }
+    }
+
+    fn remaining(&self) -> u32 {
+        (self.width as u32) * (self.height as u32) - self.index
+    }
+
+    /// Whether the last pixel returned by `next_position` ended a row.
+    fn row_complete(&self) -> bool {
+        self.index.is_multiple_of(self.width as u32)
+    }
+
+    /// Returns the VRAM position of the next pixel, wrapping at the edges.
+    fn next_position(&mut self) -> (u16, u16) {
+        let width = self.width as u32;
+
+        let x = self.x.wrapping_add((self.index % width) as u16) & 0x3ff;
+        let y = self.y.wrapping_add((self.index / width) as u16) & 0x1ff;
+
+        self.index += 1;
+
+        (x, y)
+    }
+}
+
+/// The 16 word deep GP0 command FIFO.
+struct CommandFifo {
+    buffer: [u32; 16],
+    read: u8,
+    length: u8,
 }
 
-#[derive(Clone, Copy)]
-struct HorisontalRes(u8);
+impl CommandFifo {
+    fn new() -> CommandFifo {
+        CommandFifo {
+            buffer: [0; 16],
+            read: 0,
+            length: 0,
+        }
+    }
 
-impl HorisontalRes {
-    fn from_fields(hr1: u8, hr2: u8) -> HorisontalRes {
-        let hr = (hr2 & 1) | ((hr1 & 3) << 1);
-        HorisontalRes(hr)
+    fn clear(&mut self) {
+        self.read = 0;
+        self.length = 0;
     }
 
-    fn into_status(self) -> u32 {
-        let HorisontalRes(hr) = self;
-        (hr as u32) << 16
+    fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.length as usize == self.buffer.len()
     }
 
+    fn push(&mut self, value: u32) {
+        let write = (self.read + self.length) as usize % self.buffer.len();
+
+        self.buffer[write] = value;
+        self.length += 1;
+    }
+
+    fn pop(&mut self) -> Option<u32> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let value = self.buffer[self.read as usize];
+
+        self.read = (self.read + 1) % self.buffer.len() as u8;
+        self.length -= 1;
+
+        Some(value)
+    }
 }
 
 struct CommandBuffer {
@@ -581,3 +2180,640 @@ impl ::std::ops::Index<usize> for CommandBuffer {
         &self.buffer[index]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Gpu;
+
+    /// Writes `words` to GP0, letting the GPU catch up whenever its FIFO
+    /// fills up. Returns GPUSTAT after every word.
+    fn send(gpu: &mut Gpu, words: &[u32]) -> Vec<u32> {
+        let mut status = Vec::new();
+
+        for &word in words {
+            while gpu.gp0_fifo_full() {
+                gpu.tick(16);
+            }
+
+            gpu.gp0(word);
+            gpu.tick(8);
+
+            status.push(gpu.status());
+        }
+
+        status
+    }
+
+    /// Lets the GPU run until it has executed every queued command.
+    fn wait_idle(gpu: &mut Gpu) {
+        while gpu.status() & (1 << 26) == 0 {
+            gpu.tick(1000);
+        }
+    }
+
+    fn vram_pixel(gpu: &mut Gpu, x: u16, y: u16) -> u16 {
+        gpu.renderer.sync();
+        gpu.renderer.vram().load16(x, y)
+    }
+
+    #[test]
+    fn interrupt_request_raises_irq_once() {
+        let mut gpu = Gpu::new();
+
+        gpu.gp0(0x1f000000);
+
+        assert!(gpu.status() & (1 << 24) != 0);
+        assert!(gpu.take_irq());
+        assert!(!gpu.take_irq());
+
+        // Still pending on the GPU side, no new edge.
+        gpu.gp0(0x1f000000);
+
+        assert!(!gpu.take_irq());
+
+        gpu.gp1(0x02000000);
+
+        assert!(gpu.status() & (1 << 24) == 0);
+
+        gpu.gp0(0x1f000000);
+
+        assert!(gpu.take_irq());
+    }
+
+    #[test]
+    fn reset_drops_partial_commands() {
+        let mut gpu = Gpu::new();
+
+        // Monochrome quad missing two vertices.
+        gpu.gp0(0x28ffffff);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00000010);
+
+        gpu.gp1(0x00000000);
+
+        // Taken as a command again rather than as the next vertex.
+        gpu.gp0(0x1f000000);
+
+        assert!(gpu.take_irq());
+
+        // Upload interrupted by GP1 0x01.
+        gpu.gp0(0xa0000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00100010);
+        gpu.gp0(0x12345678);
+
+        gpu.gp1(0x01000000);
+        gpu.gp1(0x02000000);
+
+        gpu.gp0(0x1f000000);
+
+        assert!(gpu.take_irq());
+        assert!(gpu.status() & (1 << 26) != 0);
+    }
+    #[test]
+    fn upload_rows_wrap_around() {
+        let mut gpu = Gpu::new();
+
+        // 3x2 pixels at (1022, 5).
+        send(&mut gpu, &[0xa0000000, 0x000503fe, 0x00020003, 0x00020001, 0x00040003, 0x00060005]);
+
+        let pixels: Vec<u16> = [(1022, 5), (1023, 5), (0, 5), (1022, 6), (1023, 6), (0, 6)].iter()
+            .map(|&(x, y)| vram_pixel(&mut gpu, x, y))
+            .collect();
+
+        assert_eq!(pixels, [1, 2, 3, 4, 5, 6]);
+    }
+
+    /// Monochrome 512x256 rectangle, keeps the GPU busy for a while.
+    const SLOW_RECTANGLE: [u32; 3] = [0x60ffffff, 0x00000000, 0x01000200];
+
+    #[test]
+    fn status_follows_fifo_and_busy_state() {
+        let mut gpu = Gpu::new();
+
+        // DMA requests follow the FIFO state.
+        gpu.gp1(0x04000001);
+
+        let bits = |status: u32| ((status >> 25) & 1, (status >> 26) & 1, (status >> 28) & 1);
+
+        // Empty FIFO, idle: DMA request, ready for a command, ready for
+        // words.
+        assert_eq!(bits(gpu.status()), (1, 1, 1));
+
+        for &word in SLOW_RECTANGLE.iter() {
+            gpu.gp0(word);
+        }
+
+        // Busy drawing with room in the FIFO.
+        assert_eq!(bits(gpu.status()), (1, 0, 1));
+
+        for _ in 0..16 {
+            gpu.gp0(0x00000000);
+        }
+
+        // Full FIFO behind the rectangle.
+        assert_eq!(bits(gpu.status()), (0, 0, 0));
+
+        // In mode 2 bit 25 mirrors bit 28.
+        gpu.gp1(0x04000002);
+
+        assert_eq!(bits(gpu.status()), (0, 0, 0));
+
+        wait_idle(&mut gpu);
+
+        assert_eq!(bits(gpu.status()), (1, 1, 1));
+    }
+
+    #[test]
+    fn fifo_overflow_drops_words() {
+        let mut gpu = Gpu::new();
+
+        for &word in SLOW_RECTANGLE.iter() {
+            gpu.gp0(word);
+        }
+
+        for _ in 0..16 {
+            gpu.gp0(0x00000000);
+        }
+
+        assert!(gpu.gp0_fifo_full());
+
+        // Lost, the FIFO is full.
+        gpu.gp0(0x1f000000);
+
+        wait_idle(&mut gpu);
+
+        assert!(!gpu.take_irq());
+        assert!(gpu.status() & (1 << 24) == 0);
+
+        // Taken once there's room again.
+        gpu.gp0(0x1f000000);
+
+        assert!(gpu.take_irq());
+    }
+
+    #[test]
+    fn empty_vertical_range_keeps_vblank() {
+        let mut gpu = Gpu::new();
+
+        // End before start.
+        gpu.gp1(0x07000000 | 0x100 | (0x10 << 10));
+
+        let mut vblanks = 0;
+
+        // Two NTSC frames worth of CPU cycles.
+        for _ in 0..2 * 263 * 3413 * 7 / 11 / 100 {
+            vblanks += gpu.tick(100).vblank_starts;
+        }
+
+        assert_eq!(vblanks, 2);
+    }
+
+    #[test]
+    fn fill_skips_displayed_field() {
+        let mut gpu = Gpu::new();
+
+        // 480 line interlaced, the top field is being drawn so odd rows
+        // are skipped.
+        gpu.gp1(0x08000024);
+
+        send(&mut gpu, &[0x020000ff, 0x00000000, 0x00040010]);
+
+        let rows: Vec<u16> = (0..4).map(|y| vram_pixel(&mut gpu, 0, y)).collect();
+
+        assert_eq!(rows, [0x001f, 0, 0x001f, 0]);
+
+        // Unless drawing to the display is allowed.
+        send(&mut gpu, &[0xe1000400, 0x0200ff00, 0x00000000, 0x00040010]);
+        wait_idle(&mut gpu);
+
+        let rows: Vec<u16> = (0..4).map(|y| vram_pixel(&mut gpu, 0, y)).collect();
+
+        assert_eq!(rows, [0x03e0; 4]);
+    }
+
+    /// Whether the GPU dithered the 16 pixel wide primitive at `(x, y)`,
+    /// drawn with colour 0x434343. The first two columns of the matrix
+    /// then round to different values.
+    fn dithered(gpu: &mut Gpu, x: u16, y: u16) -> bool {
+        wait_idle(gpu);
+
+        vram_pixel(gpu, x, y) != vram_pixel(gpu, x + 1, y)
+    }
+
+    /// Dithering enabled and drawing anywhere, then a 15bit texture at
+    /// (64, 0).
+    const DITHER_SETUP: [u32; 6] = [0xe1000200, 0xe3000000, 0xe407ffff, 0x02808080, 0x00000040, 0x00100010];
+
+    #[test]
+    fn dithering_follows_primitive_kind() {
+        let mut gpu = Gpu::new();
+
+        send(&mut gpu, &DITHER_SETUP);
+
+        // Gouraud shaded triangle.
+        send(&mut gpu, &[0x30434343, 0x00640000, 0x00434343, 0x00640010, 0x00434343, 0x00740000]);
+        // Flat triangle.
+        send(&mut gpu, &[0x20434343, 0x00640020, 0x00640030, 0x00740020]);
+        // Rectangle.
+        send(&mut gpu, &[0x60434343, 0x00640060, 0x00100010]);
+        // Texture blended triangle, then a raw one.
+        send(&mut gpu, &[0x24434343, 0x00640080, 0x00000000, 0x00640090, 0x01010000, 0x00740080, 0x00000000]);
+        send(&mut gpu, &[0x25434343, 0x006400a0, 0x00000000, 0x006400b0, 0x01010000, 0x007400a0, 0x00000000]);
+
+        assert!(dithered(&mut gpu, 0x00, 100));
+        assert!(!dithered(&mut gpu, 0x20, 100));
+        assert!(!dithered(&mut gpu, 0x60, 100));
+        assert!(dithered(&mut gpu, 0x80, 100));
+        assert!(!dithered(&mut gpu, 0xa0, 100));
+    }
+
+    #[test]
+    fn dithering_can_be_disabled() {
+        let mut gpu = Gpu::new();
+
+        gpu.set_dithering_allowed(false);
+
+        send(&mut gpu, &DITHER_SETUP);
+        send(&mut gpu, &[0x30434343, 0x00640000, 0x00434343, 0x00640010, 0x00434343, 0x00740000]);
+        send(&mut gpu, &[0x24434343, 0x00640080, 0x00000000, 0x00640090, 0x01010000, 0x00740080, 0x00000000]);
+
+        assert!(!dithered(&mut gpu, 0x00, 100));
+        assert!(!dithered(&mut gpu, 0x80, 100));
+    }
+
+    #[test]
+    fn polyline_ends_at_terminator() {
+        let mut gpu = Gpu::new();
+
+        send(&mut gpu, &[0xe3000000, 0xe407ffff]);
+
+        // Flat polyline through (0, 10), (10, 10) and (10, 20).
+        send(&mut gpu, &[0x480000ff, 0x000a0000, 0x000a000a, 0x0014000a, 0x5fff5fff, 0x1f000000]);
+        wait_idle(&mut gpu);
+
+        assert!(gpu.take_irq());
+
+        gpu.gp1(0x02000000);
+
+        // Shaded, the colour word looks like a terminator but isn't where
+        // one is expected.
+        send(&mut gpu, &[0x580000ff, 0x001e0000, 0x55005000, 0x001e000a, 0x50005000, 0x1f000000]);
+        wait_idle(&mut gpu);
+
+        assert!(gpu.take_irq());
+
+        assert_eq!(vram_pixel(&mut gpu, 5, 10), 0x001f);
+        assert_eq!(vram_pixel(&mut gpu, 10, 15), 0x001f);
+        assert_eq!(vram_pixel(&mut gpu, 10, 30), 0x0140);
+    }
+
+    fn row(gpu: &mut Gpu, x: u16, y: u16, width: u16) -> Vec<u16> {
+        wait_idle(gpu);
+
+        (0..width).map(|i| vram_pixel(gpu, (x + i) & 0x3ff, y)).collect()
+    }
+
+    #[test]
+    fn fill_rounds_to_16_pixels() {
+        let mut gpu = Gpu::new();
+
+        // 5 pixels at x 0x13, from x 0x10 to 0x1f once rounded.
+        send(&mut gpu, &[0x020000ff, 0x00000013, 0x00010005]);
+
+        assert_eq!(row(&mut gpu, 0x0f, 0, 18), [&[0][..], &[0x1f; 16], &[0]].concat());
+    }
+
+    #[test]
+    fn fill_ignores_drawing_area_and_mask() {
+        let mut gpu = Gpu::new();
+
+        // Masked pixel at (16, 0), then set and check mask.
+        send(&mut gpu, &[0xa0000000, 0x00000010, 0x00010001, 0x00008001, 0xe6000003]);
+
+        // The drawing area is still the single pixel at (0, 0).
+        send(&mut gpu, &[0x0200ff00, 0x00000010, 0x00020010]);
+
+        assert_eq!(row(&mut gpu, 16, 0, 2), [0x03e0, 0x03e0]);
+        assert_eq!(row(&mut gpu, 16, 1, 2), [0x03e0, 0x03e0]);
+    }
+
+    #[test]
+    fn copy_overlapping_rows() {
+        let mut gpu = Gpu::new();
+
+        send(&mut gpu, &[0xa0000000, 0x00000000, 0x00010008, 0x00020001, 0x00040003, 0x00060005, 0x00080007]);
+
+        // Two pixels to the right then back.
+        send(&mut gpu, &[0x80000000, 0x00000000, 0x00000002, 0x00010008]);
+
+        assert_eq!(row(&mut gpu, 0, 0, 10), [1, 2, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+        send(&mut gpu, &[0x80000000, 0x00000002, 0x00000000, 0x00010008]);
+
+        assert_eq!(row(&mut gpu, 0, 0, 10), [1, 2, 3, 4, 5, 6, 7, 8, 7, 8]);
+    }
+
+    #[test]
+    fn copy_wraps_and_applies_mask() {
+        let mut gpu = Gpu::new();
+
+        // 4 pixels straddling the right edge, the last two masked.
+        send(&mut gpu, &[0xa0000000, 0x000003fe, 0x00010004, 0x00020001, 0x80048003]);
+
+        // To (1022, 1), wrapping the same way.
+        send(&mut gpu, &[0x80000000, 0x000003fe, 0x000103fe, 0x00010004]);
+
+        assert_eq!(row(&mut gpu, 1022, 1, 4), [1, 2, 0x8003, 0x8004]);
+
+        // Copied pixels get the mask bit.
+        send(&mut gpu, &[0xe6000001, 0x80000000, 0x000003fe, 0x00020000, 0x00010004]);
+
+        assert_eq!(row(&mut gpu, 0, 2, 4), [0x8001, 0x8002, 0x8003, 0x8004]);
+
+        // Masked destination pixels are left alone.
+        send(&mut gpu, &[0xe6000002, 0xa0000000, 0x00030000, 0x00010004, 0x00060005, 0x00080007]);
+        send(&mut gpu, &[0x80000000, 0x00030000, 0x000003fe, 0x00010004]);
+
+        assert_eq!(row(&mut gpu, 1022, 0, 4), [0x0005, 0x0006, 0x8003, 0x8004]);
+    }
+
+    #[test]
+    fn image_store_streams_vram() {
+        let mut gpu = Gpu::new();
+
+        // 3x3 pixels at (1022, 20), wrapping at the right edge.
+        send(&mut gpu, &[0xa0000000, 0x001403fe, 0x00030003,
+                         0x00020001, 0x00040003, 0x00060005, 0x00080007, 0x00000009]);
+
+        send(&mut gpu, &[0xc0000000, 0x001403fe, 0x00030003]);
+        wait_idle(&mut gpu);
+
+        let mut words = Vec::new();
+
+        for _ in 0..5 {
+            assert!(gpu.status() & (1 << 27) != 0);
+
+            words.push(gpu.read());
+        }
+
+        // Rows are packed back to back, the odd last pixel padded with 0.
+        assert_eq!(words, [0x00020001, 0x00040003, 0x00060005, 0x00080007, 0x00000009]);
+
+        assert!(gpu.status() & (1 << 27) == 0);
+
+        // The latch keeps the last word once the transfer is over.
+        assert_eq!(gpu.read(), 0x00000009);
+    }
+
+    #[test]
+    fn timing_same_in_threaded_mode() {
+        let scene = [
+            // Drawing area (0, 0)-(256, 240).
+            0xe3000000, 0xe403c100,
+            // Triangle.
+            0x20ff0000, 0x000a000a, 0x00140064, 0x005a0032,
+            // Semi-transparent quad.
+            0x2a00ff00, 0x00300030, 0x003000a0, 0x00a00030, 0x00a000a0,
+            // 32x32 rectangle.
+            0x6000ff00, 0x00280028, 0x00200020,
+            0x1f000000,
+        ];
+
+        let mut sync = Gpu::new();
+        let mut threaded = Gpu::new();
+
+        threaded.set_threaded_rendering(true);
+
+        assert!(send(&mut sync, &scene) == send(&mut threaded, &scene));
+    }
+
+    #[test]
+    fn frame_stats_count_drawn_pixels() {
+        let scene = [
+            0xe3000000, 0xe407ffff,
+            // 16x16 masked rectangle.
+            0xe6000001, 0x600000ff, 0x00000000, 0x00100010,
+            // 32x16 rectangle over it, only its right half gets drawn.
+            0xe6000002, 0x6000ff00, 0x00000000, 0x00100020,
+        ];
+
+        let mut sync = Gpu::new();
+        let mut threaded = Gpu::new();
+        let mut tiled = Gpu::new();
+
+        threaded.set_threaded_rendering(true);
+        tiled.set_threaded_rendering(true);
+        tiled.set_tile_threads(4);
+
+        for gpu in [&mut sync, &mut threaded, &mut tiled] {
+            send(gpu, &scene);
+
+            let frame = gpu.frame();
+
+            while gpu.frame() == frame {
+                gpu.tick(1000);
+            }
+
+            let stats = gpu.frame_stats();
+
+            assert_eq!((stats.rectangles, stats.pixels, stats.texels), (2, 16 * 16 * 2, 0));
+        }
+    }
+
+    /// Xorshift, enough to fill textures and scatter primitives.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+
+            self.0
+        }
+
+        /// Vertex within `size` pixels of `(x, y)`.
+        fn vertex(&mut self, (x, y): (u32, u32), size: u32) -> u32 {
+            (x + self.next() % size) | ((y + self.next() % size) << 16)
+        }
+    }
+
+    /// GP0 stream exercising what makes the tiled rasterizer split its
+    /// work: primitives spanning every band, overlapping semi-transparent
+    /// primitives, textures drawn by earlier commands and sampled from the
+    /// area being drawn, fills and copies in between and mask bits.
+    fn tile_scene() -> Vec<u32> {
+        let mut r = Rng(0x2545f491);
+
+        // Drawing area over the whole VRAM.
+        let mut words = vec![0xe3000000, 0xe407ffff, 0xe5000000];
+
+        // 15bit texture at (640, 0) with transparent and semi-transparent
+        // texels, 4bit texture at (704, 0) and its CLUT at (640, 256).
+        words.extend_from_slice(&[0xa0000000, 0x00000280, 0x00400040]);
+
+        for _ in 0..64 * 64 / 2 {
+            let texel = |r: &mut Rng| match r.next() % 8 {
+                0 => 0,
+                _ => r.next() & 0xffff,
+            };
+
+            words.push(texel(&mut r) | (texel(&mut r) << 16));
+        }
+
+        words.extend_from_slice(&[0xa0000000, 0x000002c0, 0x00400010]);
+        words.extend((0..16 * 64 / 2).map(|_| r.next()));
+
+        words.extend_from_slice(&[0xa0000000, 0x01000280, 0x00010010]);
+        words.extend((0..8).map(|_| r.next() & 0x7fff7fff));
+
+        // Gouraud triangle through every row, dithered.
+        words.extend_from_slice(&[0xe1000200, 0x30ff0000, 0x00000000, 0x0000ff00, 0x006403e8, 0x000000ff, 0x01ff00c8]);
+
+        // Overlapping semi-transparent quads in every blending mode,
+        // flat and textured.
+        for mode in 0..4 {
+            words.push(0xe100020a | (mode << 5) | (2 << 7));
+            words.extend_from_slice(&[0x2a000000 | (r.next() & 0xffffff), 0x00200020, 0x002001a0, 0x01a00020, 0x01a001a0]);
+
+            let (x, y) = (40 + mode * 50, 30 + mode * 90);
+            let page = 10 | (mode << 5) | (2 << 7);
+
+            words.extend_from_slice(&[
+                0x3e808080, r.vertex((x, y), 40), 0x0000,
+                0x00ff8040, r.vertex((x + 200, y), 40), 0x3f00 | (page << 16),
+                0x004080ff, r.vertex((x, y + 150), 40), 0x003f,
+                0x00808080, r.vertex((x + 200, y + 150), 40), 0x3f3f,
+            ]);
+        }
+
+        // Heavy rectangles over the top rows and the 15bit texture, each
+        // followed by a small rectangle far below sampling that texture
+        // with a drawing area that leaves the textures out. In the same
+        // segment the band drawing the latter would get ahead of the one
+        // still drawing the former.
+        words.push(0xe100012a);
+
+        for i in 0..4 {
+            words.extend_from_slice(&[0xe407ffff, 0x62000000 | (r.next() & 0xffffff), 0x00000000, 0x007f03ff]);
+            words.extend_from_slice(&[0xe407fe7f, 0x65808080, 0x01a40000 | (i * 100), 0x00000000, 0x00400040]);
+        }
+
+        // 4bit textured triangle and rectangle, then a line.
+        words.extend_from_slice(&[0x24808080, 0x00100300, 0x40280000, 0x01f00380, 0x000b003f, 0x00500200, 0x00003f00]);
+        words.extend_from_slice(&[0x64808080, 0x00800300, 0x40280810, 0x00900040]);
+        words.extend_from_slice(&[0x50ff00ff, 0x00000000, 0x0000ff00, 0x01ff03ff]);
+
+        // Fill and copy between draws, over areas textures come from.
+        words.extend_from_slice(&[0x02204060, 0x00100280, 0x00200020]);
+        words.extend_from_slice(&[0x80000000, 0x00300040, 0x00000290, 0x00400080]);
+
+        // Textured from the page the quad is drawn on.
+        words.extend_from_slice(&[0x2c808080, 0x00140014, 0x0000, 0x001400c8, 0x010000ff, 0x00b40014, 0xff00, 0x00b400c8, 0xffff]);
+
+        // Mask bits set by one quad and honoured by the next ones.
+        words.extend_from_slice(&[0xe6000001, 0x28ff00ff, 0x00400100, 0x00400180, 0x00c00100, 0x00c00180]);
+        words.extend_from_slice(&[0xe6000002, 0x38ffffff, 0x00200120, 0x00000000, 0x002001c0, 0x00ffffff, 0x01000120, 0x00808080, 0x010001c0]);
+        words.push(0xe6000000);
+
+        // Drawing area bottom past the last VRAM line, with a triangle
+        // reaching below it.
+        words.extend_from_slice(&[0xe40fffff, 0x30204080, 0x012c0050, 0x00804020, 0x02bc00c8, 0x00408020, 0x01900190]);
+
+        // Scattered semi-transparent and textured triangles, some crossing
+        // band boundaries, with the texture page in flux. The drawing area
+        // leaves the textures out except now and then.
+        for i in 0..300 {
+            words.push(match i % 50 {
+                0 => 0xe407ffff,
+                _ => 0xe407fe7f,
+            });
+
+            let opcode = 0x20 | (r.next() & 0x17);
+            let (x, y) = (r.next() % 900, r.next() % 400);
+
+            words.push((opcode << 24) | (r.next() & 0xffffff));
+
+            for i in 0..3 {
+                if opcode & 0x10 != 0 && i > 0 {
+                    words.push(r.next() & 0xffffff);
+                }
+
+                words.push(r.vertex((x, y), 120));
+
+                if opcode & 0x04 != 0 {
+                    let attribute = match i {
+                        0 => 0x4028,
+                        1 => [0x0a | (2 << 7), 0x0b, 2 << 7][(r.next() % 3) as usize] | (r.next() & 0x60),
+                        _ => 0,
+                    };
+
+                    words.push((r.next() & 0x3f3f) | (attribute << 16));
+                }
+            }
+        }
+
+        words
+    }
+
+    /// Native VRAM followed by the upscaled copy.
+    fn vram_contents(gpu: &mut Gpu) -> Vec<u16> {
+        gpu.renderer.sync();
+
+        let vram = gpu.renderer.vram();
+
+        let mut pixels = vram.pixels().to_vec();
+
+        let scale = vram.scale();
+
+        if scale > 1 {
+            for y in 0..512 * scale {
+                for x in 0..1024 * scale {
+                    pixels.push(vram.load_upscaled(x, y));
+                }
+            }
+        }
+
+        pixels
+    }
+
+    fn tiled_matches_scalar(scale: u16, threads: usize) {
+        let scene = tile_scene();
+
+        let mut scalar = Gpu::new();
+
+        scalar.set_internal_resolution(scale);
+
+        send(&mut scalar, &scene);
+
+        let mut tiled = Gpu::new();
+
+        tiled.set_internal_resolution(scale);
+        tiled.set_tile_threads(threads);
+
+        send(&mut tiled, &scene);
+
+        let (expected, drawn) = (scalar.renderer.take_stats(), tiled.renderer.take_stats());
+
+        assert_eq!((expected.pixels, expected.texels), (drawn.pixels, drawn.texels));
+
+        let (scalar, tiled) = (vram_contents(&mut scalar), vram_contents(&mut tiled));
+
+        let first = scalar.iter().zip(tiled.iter()).position(|(a, b)| a != b);
+
+        assert!(first.is_none(), "Tiled VRAM differs at index {:?}", first);
+    }
+
+    #[test]
+    fn tiled_rendering_matches_scalar() {
+        tiled_matches_scalar(1, 4);
+        // Bands not aligned on powers of two.
+        tiled_matches_scalar(1, 3);
+    }
+
+    #[test]
+    fn tiled_rendering_matches_scalar_upscaled() {
+        tiled_matches_scalar(2, 4);
+    }
+}
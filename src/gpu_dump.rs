@@ -0,0 +1,394 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use gpu::{Gpu, TimingState, VRamView};
+use vram::{VRAM_WIDTH, VRAM_HEIGHT};
+
+const MAGIC: &[u8; 8] = b"PSXGPU\x00\x02";
+
+/// Dump file layout: the magic, the video timing as written by
+/// `write_timing`, a snapshot of VRAM as little endian 16bit pixels, then a
+/// stream of events. Each event is a tag byte, the number of CPU cycles
+/// since the previous event as a LEB128 varint and, except for vblanks, the
+/// little endian word written to the GPU.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Word written to GP0 by the CPU.
+    Gp0 = 0,
+    /// Word written to GP0 by DMA.
+    Gp0Dma = 1,
+    Gp1 = 2,
+    /// Frame boundary.
+    VBlank = 3,
+}
+
+impl Event {
+    fn from_tag(tag: u8) -> Result<Event> {
+        match tag {
+            0 => Ok(Event::Gp0),
+            1 => Ok(Event::Gp0Dma),
+            2 => Ok(Event::Gp1),
+            3 => Ok(Event::VBlank),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid GPU dump event")),
+        }
+    }
+}
+
+/// Which frames to record and where to.
+pub struct RecordRequest {
+    pub path: PathBuf,
+    /// Number of vblanks to wait for before starting.
+    pub start_frame: u32,
+    /// Number of frames to record, `None` to record until exit.
+    pub frames: Option<u32>,
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+    /// CPU cycles since the last event.
+    cycles: u32,
+    /// Frames left to record.
+    frames: Option<u32>,
+}
+
+impl Recorder {
+    /// Creates the dump file. `state` holds the GP0 and GP1 commands that
+    /// bring a freshly reset GPU to the current state.
+    pub fn create<P: AsRef<Path>>(path: P,
+                                  vram: &[u16],
+                                  timing: &TimingState,
+                                  state: &[(Event, u32)],
+                                  frames: Option<u32>) -> Result<Recorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(MAGIC)?;
+
+        write_timing(&mut writer, timing)?;
+
+        for &pixel in vram {
+            writer.write_all(&pixel.to_le_bytes())?;
+        }
+
+        let mut recorder = Recorder {
+            writer,
+            cycles: 0,
+            frames,
+        };
+
+        for &(event, value) in state {
+            recorder.record(event, value)?;
+        }
+
+        Ok(recorder)
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles;
+    }
+
+    pub fn record(&mut self, event: Event, value: u32) -> Result<()> {
+        self.writer.write_all(&[event as u8])?;
+
+        let mut cycles = self.cycles;
+
+        loop {
+            let byte = (cycles & 0x7f) as u8;
+
+            cycles >>= 7;
+
+            if cycles == 0 {
+                self.writer.write_all(&[byte])?;
+                break;
+            }
+
+            self.writer.write_all(&[byte | 0x80])?;
+        }
+
+        self.cycles = 0;
+
+        if event != Event::VBlank {
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a frame boundary, returns false once all the requested frames
+    /// have been recorded.
+    pub fn end_frame(&mut self) -> Result<bool> {
+        self.record(Event::VBlank, 0)?;
+
+        if let Some(ref mut frames) = self.frames {
+            *frames = frames.saturating_sub(1);
+
+            if *frames == 0 {
+                self.writer.flush()?;
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            println!("Failed to flush GPU dump: {}", e);
+        }
+    }
+}
+
+/// Plays a dump back into `gpu`, which should be freshly reset. `on_frame`
+/// is called at every frame boundary with the frame number.
+pub fn replay<P, F>(path: P, mut gpu: Gpu, mut on_frame: F) -> Result<Gpu>
+    where P: AsRef<Path>,
+          F: FnMut(&mut Gpu, u32)
+{
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 8];
+
+    reader.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a GPU dump"));
+    }
+
+    let timing = read_timing(&mut reader)?;
+
+    let mut bytes = vec![0; VRAM_WIDTH * VRAM_HEIGHT * 2];
+
+    reader.read_exact(&mut bytes)?;
+
+    let vram: Vec<u16> = bytes.chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect();
+
+    gpu.load_vram(&vram);
+    gpu.set_timing_state(&timing);
+
+    let mut frame = 0;
+
+    loop {
+        let mut tag = [0];
+
+        if reader.read(&mut tag)? == 0 {
+            break;
+        }
+
+        let event = Event::from_tag(tag[0])?;
+
+        let cycles = read_varint(&mut reader)?;
+
+        if cycles > 0 {
+            gpu.tick(cycles);
+        }
+
+        if event == Event::VBlank {
+            on_frame(&mut gpu, frame);
+            frame += 1;
+            continue;
+        }
+
+        let mut word = [0; 4];
+
+        reader.read_exact(&mut word)?;
+
+        let value = u32::from_le_bytes(word);
+
+        match event {
+            Event::Gp0 => gpu.gp0(value),
+            Event::Gp0Dma => {
+                // Same as the DMA controller: wait for room in the FIFO.
+                while gpu.gp0_fifo_full() {
+                    let cycles = gpu.busy_cpu_cycles();
+
+                    gpu.tick(cycles);
+                }
+
+                gpu.gp0(value);
+            },
+            Event::Gp1 => gpu.gp1(value),
+            Event::VBlank => unreachable!(),
+        }
+    }
+
+    Ok(gpu)
+}
+
+/// Little endian words for the line, the cycles into it, the clock and dot
+/// fractions and the busy cycles, then a byte for the field and one for
+/// vblank.
+fn write_timing<W: Write>(writer: &mut W, timing: &TimingState) -> Result<()> {
+    let words = [
+        timing.line as u32,
+        timing.line_cycles,
+        timing.clock_fraction,
+        timing.dot_fraction,
+        timing.busy_cycles,
+    ];
+
+    for word in words {
+        writer.write_all(&word.to_le_bytes())?;
+    }
+
+    writer.write_all(&[timing.top_field as u8, timing.in_vblank as u8])
+}
+
+fn read_timing<R: Read>(reader: &mut R) -> Result<TimingState> {
+    let mut bytes = [0; 22];
+
+    reader.read_exact(&mut bytes)?;
+
+    let word = |i: usize| u32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+
+    Ok(TimingState {
+        line: word(0) as u16,
+        line_cycles: word(1),
+        clock_fraction: word(2),
+        dot_fraction: word(3),
+        busy_cycles: word(4),
+        top_field: bytes[20] != 0,
+        in_vblank: bytes[21] != 0,
+    })
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let mut byte = [0];
+
+        reader.read_exact(&mut byte)?;
+
+        value |= ((byte[0] & 0x7f) as u32) << shift;
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+
+        shift += 7;
+
+        if shift >= 32 {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid GPU dump varint"));
+        }
+    }
+}
+
+/// Replays a dump with the scalar rasterizer and with the tile-parallel one
+/// and returns the first frame where VRAM or the displayed picture differ.
+/// Both run on the worker thread so that they see the same GPU timings.
+pub fn compare_tiles<P: AsRef<Path>>(path: P, threads: usize) -> Result<Option<u32>> {
+    let mut scalar = Gpu::new();
+
+    scalar.set_threaded_rendering(true);
+
+    let mut hashes = Vec::new();
+
+    replay(&path, scalar, |gpu, _| hashes.push(frame_hash(gpu)))?;
+
+    let mut tiled = Gpu::new();
+
+    tiled.set_tile_threads(threads);
+
+    let mut mismatch = None;
+
+    replay(&path, tiled, |gpu, frame| {
+        if mismatch.is_none() && hashes.get(frame as usize) != Some(&frame_hash(gpu)) {
+            mismatch = Some(frame);
+        }
+    })?;
+
+    Ok(mismatch)
+}
+
+fn frame_hash(gpu: &mut Gpu) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    gpu.vram_frame(VRamView::Bits15).pixels.hash(&mut hasher);
+    gpu.display_frame().pixels.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use gpu::{Gpu, VRamView};
+
+    use super::replay;
+
+    fn send(gpu: &mut Gpu, words: &[u32]) {
+        for &word in words {
+            while gpu.gp0_fifo_full() {
+                gpu.tick(16);
+            }
+
+            gpu.gp0(word);
+        }
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let path = env::temp_dir().join(format!("gpu_dump_test_{}.dump", process::id()));
+
+        let _ = fs::remove_file(&path);
+
+        let mut gpu = Gpu::new();
+
+        // Interlaced 480 lines, fills then skip the displayed field.
+        gpu.gp1(0x08000024);
+        send(&mut gpu, &[0xe3000000, 0xe407ffff]);
+
+        gpu.tick(100_000);
+
+        // Drawing a 512x256 rectangle: the recording only starts once the
+        // GPU is done, mid-frame.
+        send(&mut gpu, &[0x60ffffff, 0x00000000, 0x01000200]);
+        gpu.record_to(path.clone(), 0, Some(3));
+
+        assert!(!path.exists());
+
+        let start = gpu.frame();
+
+        while !path.exists() {
+            gpu.tick(97);
+        }
+
+        assert_eq!(gpu.frame(), start);
+
+        let mut color = 0x10;
+
+        while gpu.frame() < start + 3 {
+            send(&mut gpu, &[0x02000000 | color, 0x00400040 + color, 0x00200020,
+                             0x30ff0000, 0x00100010, 0x00ff0000 | color, 0x01000040, 0x000000ff, 0x00800100]);
+
+            // Stop right where the recording ends.
+            for _ in 0..400 {
+                if gpu.frame() == start + 3 {
+                    break;
+                }
+
+                gpu.tick(97 + color);
+            }
+
+            color += 1;
+        }
+
+        let mut replayed = replay(&path, Gpu::new(), |_, _| ()).unwrap();
+
+        assert_eq!(replayed.timing_state(), gpu.timing_state());
+
+        let pixels = |gpu: &mut Gpu| gpu.vram_frame(VRamView::Bits15).pixels;
+
+        assert!(pixels(&mut replayed) == pixels(&mut gpu));
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::Write;
+use std::ops::AddAssign;
+
+use rasterizer::DrawStats;
+
+/// GPU work done during one frame, or summed over several.
+#[derive(Clone, Copy, Default)]
+pub struct FrameStats {
+    pub triangles: u64,
+    pub quads: u64,
+    pub rectangles: u64,
+    /// Single lines and polyline segments.
+    pub lines: u64,
+    pub fills: u64,
+    pub copies: u64,
+    /// Pixels written by primitives, fills and copies, and texels fetched
+    /// by textured primitives, as drawn by the renderer rather than the
+    /// estimate the drawing time is based on.
+    pub pixels: u64,
+    pub texels: u64,
+    /// GP0 0xA0 transfers and the number of pixels they carried.
+    pub uploads: u64,
+    pub upload_pixels: u64,
+    /// GP0 0xC0 transfers and the number of pixels they carried.
+    pub downloads: u64,
+    pub download_pixels: u64,
+    pub gp1_writes: u64,
+    /// Linked list DMA packets walked.
+    pub dma_packets: u64,
+}
+
+impl AddAssign for FrameStats {
+    fn add_assign(&mut self, other: FrameStats) {
+        self.triangles += other.triangles;
+        self.quads += other.quads;
+        self.rectangles += other.rectangles;
+        self.lines += other.lines;
+        self.fills += other.fills;
+        self.copies += other.copies;
+        self.pixels += other.pixels;
+        self.texels += other.texels;
+        self.uploads += other.uploads;
+        self.upload_pixels += other.upload_pixels;
+        self.downloads += other.downloads;
+        self.download_pixels += other.download_pixels;
+        self.gp1_writes += other.gp1_writes;
+        self.dma_packets += other.dma_packets;
+    }
+}
+
+impl FrameStats {
+    pub fn add_draw(&mut self, stats: DrawStats) {
+        self.pixels += stats.pixels as u64;
+        self.texels += stats.texels as u64;
+    }
+
+    pub fn primitives(&self) -> u64 {
+        self.triangles + self.quads + self.rectangles + self.lines
+    }
+
+    /// Counters by name, in report order.
+    fn counters(&self) -> [(&'static str, u64); 15] {
+        [
+            ("triangles", self.triangles),
+            ("quads", self.quads),
+            ("rectangles", self.rectangles),
+            ("lines", self.lines),
+            ("fills", self.fills),
+            ("copies", self.copies),
+            ("pixels written", self.pixels),
+            ("texels fetched", self.texels),
+            ("uploads", self.uploads),
+            ("upload pixels", self.upload_pixels),
+            ("downloads", self.downloads),
+            ("download pixels", self.download_pixels),
+            ("GP1 writes", self.gp1_writes),
+            ("DMA packets", self.dma_packets),
+            ("primitives", self.primitives()),
+        ]
+    }
+}
+
+/// Counters of the frame in progress along with the history needed for
+/// the report.
+pub struct GpuStats {
+    current: FrameStats,
+    last: FrameStats,
+    total: FrameStats,
+    frames: u32,
+    /// Frame that wrote the most pixels.
+    busiest: Option<(u32, FrameStats)>,
+}
+
+impl GpuStats {
+    pub fn new() -> GpuStats {
+        GpuStats {
+            current: FrameStats::default(),
+            last: FrameStats::default(),
+            total: FrameStats::default(),
+            frames: 0,
+            busiest: None,
+        }
+    }
+
+    pub fn current_mut(&mut self) -> &mut FrameStats {
+        &mut self.current
+    }
+
+    /// Last complete frame.
+    pub fn last(&self) -> &FrameStats {
+        &self.last
+    }
+
+    pub fn end_frame(&mut self, frame: u32) {
+        let stats = self.current;
+
+        let busier = match self.busiest {
+            Some((_, ref busiest)) => stats.pixels > busiest.pixels,
+            None => true,
+        };
+
+        if busier {
+            self.busiest = Some((frame, stats));
+        }
+
+        self.total += stats;
+        self.last = stats;
+        self.frames += 1;
+
+        self.current = FrameStats::default();
+    }
+
+    /// Table of the totals, the average per frame and the busiest frame.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+
+        let _ = writeln!(report, "GPU statistics over {} frames", self.frames);
+
+        let busiest = match self.busiest {
+            Some((frame, stats)) => {
+                let _ = writeln!(report, "{:>16} {:>12} {:>12} {:>12}", "", "total", "per frame", format!("frame {}", frame));
+                stats
+            },
+            None => {
+                let _ = writeln!(report, "{:>16} {:>12} {:>12}", "", "total", "per frame");
+                FrameStats::default()
+            },
+        };
+
+        let frames = self.frames.max(1) as u64;
+
+        for (&(name, total), &(_, peak)) in self.total.counters().iter().zip(busiest.counters().iter()) {
+            let _ = write!(report, "{:>16} {:>12} {:>12}", name, total, total / frames);
+
+            if self.busiest.is_some() {
+                let _ = write!(report, " {:>12}", peak);
+            }
+
+            report.push('\n');
+        }
+
+        report
+    }
+}
@@ -0,0 +1,730 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+/// Largest block a stored (uncompressed) deflate block can hold.
+const STORED_BLOCK_MAX: usize = 0xffff;
+
+/// Order the code length code lengths of a dynamic deflate block are
+/// stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Base lengths and extra bits of deflate length symbols 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances and extra bits of deflate distance symbols.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Saves an RGBA image as PPM if the file name ends with `.ppm`, as PNG
+/// otherwise.
+pub fn save<P: AsRef<Path>>(path: P, width: u32, height: u32, rgba: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+
+    let data = match path.extension().and_then(|e| e.to_str()) {
+        Some("ppm") => encode_ppm(width, height, rgba),
+        _ => encode_png(width, height, rgba),
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writer.write_all(&data)?;
+    writer.flush()
+}
+
+/// Loads an 8bit PNG or a binary PPM as RGBA, returning its width, height
+/// and pixels.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(u32, u32, Vec<u8>)> {
+    let path = path.as_ref();
+
+    let data = fs::read(path)?;
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("ppm") => decode_ppm(&data),
+        _ => decode_png(&data),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Bytes taken by `width` x `height` pixels of `bytes` bytes each, an error
+/// if that overflows.
+fn image_size(width: u32, height: u32, bytes: usize) -> Result<usize> {
+    (width as usize).checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(bytes))
+        .ok_or_else(|| invalid("Image too large"))
+}
+
+fn decode_ppm(data: &[u8]) -> Result<(u32, u32, Vec<u8>)> {
+    // Magic, width, height and maximum value separated by whitespace, then
+    // a single whitespace character before the pixels.
+    let mut fields = Vec::new();
+    let mut pos = 0;
+
+    while fields.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+
+        let start = pos;
+
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+
+        if start == pos {
+            return Err(invalid("Truncated PPM header"));
+        }
+
+        fields.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+    }
+
+    let number = |s: &str| s.parse::<u32>().map_err(|_| invalid("Bad PPM header"));
+
+    if fields[0] != "P6" || number(&fields[3])? != 255 {
+        return Err(invalid("Only 8bit binary PPM is supported"));
+    }
+
+    let (width, height) = (number(&fields[1])?, number(&fields[2])?);
+
+    let pixels = &data[(pos + 1).min(data.len())..];
+
+    if pixels.len() < image_size(width, height, 3)? {
+        return Err(invalid("Truncated PPM"));
+    }
+
+    let mut rgba = Vec::with_capacity(image_size(width, height, 4)?);
+
+    for pixel in pixels.chunks(3).take(image_size(width, height, 1)?) {
+        rgba.extend_from_slice(pixel);
+        rgba.push(0xff);
+    }
+
+    Ok((width, height, rgba))
+}
+
+/// Non-interlaced 8bit greyscale, RGB or RGBA PNG, with or without alpha.
+fn decode_png(data: &[u8]) -> Result<(u32, u32, Vec<u8>)> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Err(invalid("Not a PNG file"));
+    }
+
+    let mut pos = 8;
+    let mut header = None;
+    let mut compressed = Vec::new();
+    let mut complete = false;
+
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+
+        let end = (pos + 8).checked_add(len).ok_or_else(|| invalid("Bad PNG chunk length"))?;
+
+        let payload = data.get(pos + 8..end).ok_or_else(|| invalid("Truncated PNG chunk"))?;
+
+        match kind {
+            b"IHDR" if len >= 13 => header = Some(payload.to_vec()),
+            b"IDAT" => compressed.extend_from_slice(payload),
+            b"IEND" => {
+                complete = true;
+                break;
+            },
+            _ => (),
+        }
+
+        // Skip the CRC too.
+        pos = end + 4;
+    }
+
+    if !complete {
+        return Err(invalid("Truncated PNG"));
+    }
+
+    let header = header.ok_or_else(|| invalid("PNG without header"))?;
+
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    let channels = match header[9] {
+        0 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(invalid("Unsupported PNG colour type")),
+    };
+
+    if header[8] != 8 || header[12] != 0 {
+        return Err(invalid("Only 8bit non-interlaced PNG is supported"));
+    }
+
+    // Skip the zlib header, the checksum at the end isn't checked.
+    let raw = inflate(compressed.get(2..).unwrap_or(&[]))?;
+
+    let stride = image_size(width, 1, channels)?;
+
+    // Every line starts with its filter type.
+    if raw.len() < image_size(height, 1, stride + 1)? {
+        return Err(invalid("Truncated PNG image data"));
+    }
+
+    let mut pixels = vec![0u8; image_size(width, height, channels)?];
+
+    for y in 0..height as usize {
+        let filter = raw[y * (stride + 1)];
+        let line = &raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+
+        for x in 0..stride {
+            let a = match x >= channels {
+                true => pixels[y * stride + x - channels] as i32,
+                false => 0,
+            };
+            let b = match y > 0 {
+                true => pixels[(y - 1) * stride + x] as i32,
+                false => 0,
+            };
+            let c = match x >= channels && y > 0 {
+                true => pixels[(y - 1) * stride + x - channels] as i32,
+                false => 0,
+            };
+
+            let predictor = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => (a + b) / 2,
+                4 => paeth(a, b, c),
+                _ => return Err(invalid("Bad PNG filter type")),
+            };
+
+            pixels[y * stride + x] = line[x].wrapping_add(predictor as u8);
+        }
+    }
+
+    let mut rgba = Vec::with_capacity(image_size(width, height, 4)?);
+
+    for pixel in pixels.chunks(channels) {
+        match channels {
+            1 => rgba.extend_from_slice(&[pixel[0], pixel[0], pixel[0], 0xff]),
+            2 => rgba.extend_from_slice(&[pixel[0], pixel[0], pixel[0], pixel[1]]),
+            3 => rgba.extend_from_slice(&[pixel[0], pixel[1], pixel[2], 0xff]),
+            _ => rgba.extend_from_slice(pixel),
+        }
+    }
+
+    Ok((width, height, rgba))
+}
+
+fn paeth(a: i32, b: i32, c: i32) -> i32 {
+    let p = a + b - c;
+    let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reads deflate data least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+
+        for i in 0..count {
+            let byte = *self.data.get(self.pos).ok_or_else(|| invalid("Truncated deflate stream"))?;
+
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+
+            self.bit += 1;
+
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// Canonical Huffman code, stored as the number of codes of each length
+/// and the symbols sorted by code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+
+        counts[0] = 0;
+
+        let mut symbols = Vec::with_capacity(lengths.len());
+
+        for len in 1..16 {
+            for (symbol, &l) in lengths.iter().enumerate() {
+                if l as usize == len {
+                    symbols.push(symbol as u16);
+                }
+            }
+        }
+
+        Huffman {
+            counts,
+            symbols,
+        }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+
+            let count = self.counts[len] as i32;
+
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(invalid("Bad Huffman code"))
+    }
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = BitReader {
+        data,
+        pos: 0,
+        bit: 0,
+    };
+
+    let mut out = Vec::new();
+
+    loop {
+        let last = reader.bits(1)? != 0;
+
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+
+                let header = data.get(reader.pos..reader.pos + 4).ok_or_else(|| invalid("Truncated stored block"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+
+                if u16::from_le_bytes([header[2], header[3]]) != !len {
+                    return Err(invalid("Bad stored block length"));
+                }
+
+                let len = len as usize;
+
+                let start = reader.pos + 4;
+                let block = data.get(start..start + len).ok_or_else(|| invalid("Truncated stored block"))?;
+
+                out.extend_from_slice(block);
+
+                reader.pos = start + len;
+            },
+            1 => {
+                let mut lengths = [0u8; 288];
+
+                for (symbol, len) in lengths.iter_mut().enumerate() {
+                    *len = match symbol {
+                        0..=143 => 8,
+                        144..=255 => 9,
+                        256..=279 => 7,
+                        _ => 8,
+                    };
+                }
+
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            },
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            },
+            _ => return Err(invalid("Bad deflate block type")),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+
+    for &i in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[i] = reader.bits(3)? as u8;
+    }
+
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(reader)? {
+            16 => {
+                let previous = *lengths.last().ok_or_else(|| invalid("Repeat without a length"))?;
+
+                (previous, 3 + reader.bits(2)?)
+            },
+            17 => (0, 3 + reader.bits(3)?),
+            18 => (0, 11 + reader.bits(7)?),
+            len => (len as u8, 1),
+        };
+
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+
+    if lengths.len() > literal_count + distance_count {
+        return Err(invalid("Too many code lengths"));
+    }
+
+    let (literals, distances) = lengths.split_at(literal_count);
+
+    Ok((Huffman::new(literals), Huffman::new(distances)))
+}
+
+fn inflate_block(reader: &mut BitReader,
+                 out: &mut Vec<u8>,
+                 literals: &Huffman,
+                 distances: &Huffman) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+
+                if i >= LENGTH_BASE.len() {
+                    return Err(invalid("Bad length symbol"));
+                }
+
+                let len = LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i] as u32)? as usize;
+
+                let d = distances.decode(reader)? as usize;
+
+                if d >= DISTANCE_BASE.len() {
+                    return Err(invalid("Bad distance symbol"));
+                }
+
+                let distance = DISTANCE_BASE[d] as usize + reader.bits(DISTANCE_EXTRA[d] as u32)? as usize;
+
+                if distance > out.len() {
+                    return Err(invalid("Distance before the start of the data"));
+                }
+
+                // The copy may overlap what it produces.
+                for _ in 0..len {
+                    let byte = out[out.len() - distance];
+
+                    out.push(byte);
+                }
+            },
+        }
+    }
+}
+
+/// Binary PPM, the alpha channel is dropped.
+pub fn encode_ppm(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+
+    for pixel in rgba.chunks(4) {
+        data.extend_from_slice(&pixel[..3]);
+    }
+
+    data
+}
+
+/// 8bit RGBA PNG. The image data isn't compressed, it's wrapped in stored
+/// deflate blocks which keeps the encoder trivial.
+pub fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut header = Vec::new();
+
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, colour type 6 (RGBA), default compression, filter and no
+    // interlacing.
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    write_chunk(&mut data, b"IHDR", &header);
+
+    // Every scanline starts with its filter type, 0 for none.
+    let stride = width as usize * 4;
+
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+
+    for line in rgba.chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    write_chunk(&mut data, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut data, b"IEND", &[]);
+
+    data
+}
+
+fn write_chunk(data: &mut Vec<u8>, kind: &[u8; 4], payload: &[u8]) {
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+
+    let start = data.len();
+
+    data.extend_from_slice(kind);
+    data.extend_from_slice(payload);
+
+    let crc = crc32(&data[start..]);
+
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(raw: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window and no preset dictionary.
+    let mut data = vec![0x78, 0x01];
+
+    let mut blocks = raw.chunks(STORED_BLOCK_MAX).peekable();
+
+    if blocks.peek().is_none() {
+        // An empty stream still needs a final block.
+        data.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        data.push(last as u8);
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(&(!len).to_le_bytes());
+        data.extend_from_slice(block);
+    }
+
+    data.extend_from_slice(&adler32(raw).to_be_bytes());
+
+    data
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &b in data {
+        crc ^= b as u32;
+
+        for _ in 0..8 {
+            crc = match crc & 1 != 0 {
+                true => (crc >> 1) ^ 0xedb88320,
+                false => crc >> 1,
+            };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_png, decode_ppm, encode_png, encode_ppm};
+
+    /// 5x4 RGB, every filter type, fixed Huffman codes.
+    const FIXED_PNG: [u8; 123] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x04, 0x08, 0x02, 0x00, 0x00, 0x00, 0xc9, 0x51, 0x62,
+        0x17, 0x00, 0x00, 0x00, 0x42, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x63, 0x60, 0x60, 0xf8, 0xaf,
+        0xc1, 0xf0, 0x3a, 0x80, 0xe1, 0x7a, 0x05, 0xc3, 0xe1, 0x05, 0x0c, 0xeb, 0x19, 0xd9, 0x19, 0x1e,
+        0x6a, 0xf0, 0xbe, 0x81, 0x23, 0x26, 0x76, 0x86, 0x47, 0xec, 0xbc, 0x8f, 0xd8, 0xa5, 0x1e, 0xb1,
+        0xab, 0x3f, 0x62, 0x37, 0x79, 0xc4, 0xcc, 0xc7, 0xe0, 0x22, 0x21, 0xf5, 0x5c, 0x42, 0xf1, 0xb9,
+        0x84, 0xfa, 0x73, 0x09, 0xbd, 0xe7, 0x00, 0xa4, 0x1b, 0x15, 0x23, 0x8b, 0x23, 0x27, 0x4d, 0x00,
+        0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    /// 16x12 greyscale and alpha, every filter type, dynamic Huffman codes.
+    const DYNAMIC_PNG: [u8; 172] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x0c, 0x08, 0x04, 0x00, 0x00, 0x00, 0xc1, 0xee, 0xf5,
+        0x0a, 0x00, 0x00, 0x00, 0x73, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x85, 0x90, 0xeb, 0x09, 0xc0,
+        0x20, 0x0c, 0x84, 0xcf, 0xc7, 0x1c, 0x4e, 0x92, 0xc1, 0xf2, 0xb3, 0xdd, 0xc9, 0xfd, 0xd2, 0x48,
+        0xae, 0x14, 0x45, 0x2c, 0x87, 0x87, 0xe6, 0x81, 0x1f, 0x07, 0x00, 0xe6, 0x82, 0x98, 0xcb, 0x5d,
+        0x4d, 0xe1, 0xc7, 0x3a, 0xba, 0xb9, 0x90, 0x46, 0x73, 0xb8, 0x20, 0xee, 0xc2, 0x8a, 0xb0, 0x9e,
+        0x81, 0x6f, 0x68, 0xe7, 0x05, 0x77, 0x3c, 0x1a, 0x4b, 0x8d, 0x8d, 0xc6, 0xa1, 0x7a, 0xda, 0x0e,
+        0xb7, 0xc0, 0x14, 0x62, 0x2a, 0x31, 0x3b, 0x31, 0x13, 0x30, 0x43, 0xad, 0xb0, 0xf9, 0xef, 0x8b,
+        0x82, 0x6b, 0x86, 0x5a, 0x61, 0xeb, 0x19, 0xd0, 0x3d, 0x72, 0x04, 0x73, 0x14, 0xe6, 0xa8, 0xcc,
+        0x71, 0x40, 0xda, 0x3e, 0xc1, 0x17, 0xf6, 0x01, 0x73, 0x16, 0x65, 0xa4, 0xfc, 0x61, 0x26, 0xae,
+        0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    fn pattern(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height * 4).map(|i| (i * 37 + i / 7) as u8).collect()
+    }
+
+    #[test]
+    fn png_round_trip() {
+        // Large enough for several stored blocks.
+        for &(width, height) in &[(1, 1), (7, 5), (300, 70)] {
+            let rgba = pattern(width, height);
+
+            let decoded = decode_png(&encode_png(width, height, &rgba)).unwrap();
+
+            assert!(decoded == (width, height, rgba));
+        }
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let rgba = pattern(6, 3);
+
+        let (width, height, decoded) = decode_ppm(&encode_ppm(6, 3, &rgba)).unwrap();
+
+        assert_eq!((width, height), (6, 3));
+
+        for (pixel, original) in decoded.chunks(4).zip(rgba.chunks(4)) {
+            assert_eq!(pixel[..3], original[..3]);
+            assert_eq!(pixel[3], 0xff);
+        }
+    }
+
+    #[test]
+    fn compressed_png() {
+        let (width, height, rgba) = decode_png(&FIXED_PNG).unwrap();
+
+        assert_eq!((width, height), (5, 4));
+
+        for (i, pixel) in rgba.chunks(4).enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+
+            let expected = [(x * 40 + y * 7) as u8, (x * y * 13) as u8, (255 - x * 20 - y * 30) as u8, 0xff];
+
+            assert_eq!(pixel, expected);
+        }
+
+        let (width, height, rgba) = decode_png(&DYNAMIC_PNG).unwrap();
+
+        assert_eq!((width, height), (16, 12));
+
+        for (i, pixel) in rgba.chunks(4).enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+
+            let grey = ((x / 4) * 60) as u8;
+            let alpha = match (x + y) % 3 {
+                0 => 0,
+                _ => 0xff,
+            };
+
+            assert_eq!(pixel, [grey, grey, grey, alpha]);
+        }
+    }
+
+    #[test]
+    fn truncated_png() {
+        let encoded = encode_png(7, 5, &pattern(7, 5));
+
+        // Everything up to the IEND chunk is needed.
+        for data in [&encoded[..], &FIXED_PNG, &DYNAMIC_PNG] {
+            for len in 0..data.len() - 12 {
+                assert!(decode_png(&data[..len]).is_err(), "Truncated to {} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_ppm() {
+        let encoded = encode_ppm(6, 3, &pattern(6, 3));
+
+        for len in 0..encoded.len() {
+            assert!(decode_ppm(&encoded[..len]).is_err(), "Truncated to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn corrupt_png() {
+        // Whatever byte is damaged, decoding fails cleanly or succeeds but
+        // never panics.
+        for data in [&FIXED_PNG[..], &DYNAMIC_PNG[..]] {
+            for i in 0..data.len() {
+                for flip in [0x01, 0x80, 0xff] {
+                    let mut corrupt = data.to_vec();
+
+                    corrupt[i] ^= flip;
+
+                    let _ = decode_png(&corrupt);
+                }
+            }
+        }
+
+        // Sizes whose byte count overflows.
+        let mut huge = encode_png(1, 1, &[0; 4]);
+
+        huge[16..24].copy_from_slice(&[0xff; 8]);
+
+        assert!(decode_png(&huge).is_err());
+
+        assert!(decode_ppm(b"P6\n4294967295 4294967295\n255\n\0\0\0").is_err());
+    }
+}
@@ -1,9 +1,11 @@
 use bios::Bios;
 use ram::Ram;
-use dma::Dma;
+use dma::{self, Dma};
 use dma::Port;
 use channel::*;
 use gpu::Gpu;
+use irq::{Interrupt, InterruptState};
+use timers::Timers;
 
 mod map {
     pub struct Range(u32, u32);
@@ -65,6 +67,13 @@ pub struct Interconnect {
     ram: Ram,
     dma: Dma,
     gpu: Gpu,
+    irq: InterruptState,
+    timers: Timers,
+    /// Linked list nodes visited, stamped with the number of the transfer
+    /// that visited them. Kept between transfers so that finding loops
+    /// doesn't allocate every frame.
+    linked_list_visits: Vec<u32>,
+    linked_list_transfer: u32,
 }
 
 impl Interconnect {
@@ -74,6 +83,10 @@ impl Interconnect {
             ram: Ram::new(),
             dma: Dma::new(),
             gpu: Gpu::new(),
+            irq: InterruptState::new(),
+            timers: Timers::new(),
+            linked_list_visits: vec![0; 2 * 1024 * 1024 / 4],
+            linked_list_transfer: 0,
         }
     }
 
@@ -128,21 +141,17 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::TIMERS.contains(masked_address) {
-            println!("Unimplemented TIMERS register: {:#08x}", offset);
-            return;
+            return self.timers.store(offset, value as u32);
         }
 
         if let Some(offset) = map::INTERRUPT_CONTROL.contains(masked_address) {
-            match offset {
-                _ => println!("Unimplemented INTERRUPT_CONTROL yet. Register: {:#08x} : {:08x}", offset, value),
-            }
-            return;
+            return self.set_interrupt_reg(offset, value as u32);
         }
 
         panic!("Unaligned store 16bit address {:08x}", masked_address);
     }
 
-    pub fn load16(&self, addr: u32) -> u16 {
+    pub fn load16(&mut self, addr: u32) -> u16 {
         if addr % 2 != 0 {
             panic!("Address is not equel for 16bit address {:08x}", addr);    
         }
@@ -159,8 +168,11 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::INTERRUPT_CONTROL.contains(masked_address) {
-            println!("Unimplemented INTERRUPT_CONTROL yet. Register: {:#08x}", offset);
-            return 0;
+            return self.interrupt_reg(offset) as u16;
+        }
+
+        if let Some(offset) = map::TIMERS.contains(masked_address) {
+            return self.timers.load(offset) as u16;
         }
 
         panic!("Unhandled fetch 16bit address {:08x}", addr);
@@ -205,15 +217,11 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::INTERRUPT_CONTROL.contains(masked_address) {
-            match offset {
-                _ => println!("Unimplemented INTERRUPT_CONTROL yet. Register: {:#08x} : {:08x}", offset, value),
-            }
-            return;
+            return self.set_interrupt_reg(offset, value);
         }
 
         if let Some(offset) = map::TIMERS.contains(masked_address) {
-            println!("Unimplemented TIMERS register: {:#08x}", offset);
-            return;
+            return self.timers.store(offset, value);
         }
 
         if let Some(offset) = map::DMA.contains(masked_address) {
@@ -226,13 +234,15 @@ impl Interconnect {
                 4 => self.gpu.gp1(value),
                 _ => panic!("GPU write {} {}", offset, value)
             }
+
+            self.check_gpu_irq();
             return;
         }
 
         panic!("Unhandled store 32bit address {:08x}", masked_address);
     }
 
-    pub fn load32(&self, addr: u32) -> u32 {
+    pub fn load32(&mut self, addr: u32) -> u32 {
         if addr % 4 != 0 {
             panic!("Address is not equel for 32bit address {:08x}", addr);    
         }
@@ -248,8 +258,7 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::INTERRUPT_CONTROL.contains(masked_address) {
-            println!("Unimplemented INTERRUPT_CONTROL yet. Register: {:#08x}", offset);
-            return 0;
+            return self.interrupt_reg(offset);
         }
 
         if let Some(offset) = map::DMA.contains(masked_address) {
@@ -257,13 +266,13 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::TIMERS.contains(masked_address) {
-            println!("Unimplemented TIMERS register: {:#08x}", offset);
-            return 0;
+            return self.timers.load(offset);
         }
 
         if let Some(offset) = map::GPU.contains(masked_address) {
             return match offset {
-                4 => 0x1c000000,
+                0 => self.gpu.read(),
+                4 => self.gpu.status(),
                 _ => 0,
             }
         }
@@ -271,6 +280,53 @@ impl Interconnect {
         panic!("Unhandled fetch 32bit address {:08x}", masked_address);
     }
 
+    pub fn tick(&mut self, cycles: u32) {
+        let video = self.gpu.tick(cycles);
+
+        if video.vblank_starts > 0 {
+            self.irq.assert(Interrupt::VBlank);
+        }
+
+        // Commands waiting in the FIFO may have run.
+        self.check_gpu_irq();
+
+        self.timers.tick(cycles, &video, &mut self.irq);
+    }
+
+    fn check_gpu_irq(&mut self) {
+        if self.gpu.take_irq() {
+            self.irq.assert(Interrupt::Gpu);
+        }
+    }
+
+    pub fn irq_active(&self) -> bool {
+        self.irq.active()
+    }
+
+    pub fn gpu_mut(&mut self) -> &mut Gpu {
+        &mut self.gpu
+    }
+
+    pub fn set_linked_list_limits(&mut self, max_packets: u32, max_words: u32) {
+        self.dma.set_linked_list_limits(max_packets, max_words);
+    }
+
+    fn interrupt_reg(&self, offset: u32) -> u32 {
+        match offset {
+            0 => self.irq.status() as u32,
+            4 => self.irq.mask() as u32,
+            _ => panic!("Unhandled INTERRUPT_CONTROL read {:x}", offset),
+        }
+    }
+
+    fn set_interrupt_reg(&mut self, offset: u32, value: u32) {
+        match offset {
+            0 => self.irq.ack(value as u16),
+            4 => self.irq.set_mask(value as u16),
+            _ => panic!("Unhandled INTERRUPT_CONTROL write {:x}", offset),
+        }
+    }
+
     fn dma_reg(&self, offset: u32) -> u32 {
         let major = (offset & 0x70) >> 4;
         let minor = offset & 0xf;
@@ -342,45 +398,119 @@ impl Interconnect {
     }
 
     fn do_dma_linked_list(&mut self, port: Port) {
-        let channel = self.dma.channel_mut(port);
+        let (max_packets, max_words) = self.dma.linked_list_limits();
+
+        let channel = self.dma.channel(port);
 
-        let mut addr = channel.base() & 0x1ffffc;
+        let base = channel.base() & 0x1ffffc;
+        let mut addr = base;
 
         if channel.direction() == Direction::ToRam {
-            panic!("Invalid DMA direction for dma linked list");
+            println!("Ignoring linked list DMA towards RAM. Port: {}", port as u8);
+            self.dma.channel_mut(port).done();
+            return;
         }
 
         if port != Port::GPU {
-            panic!("Attempted linked list DMA. Port: {}", port as u8);
+            println!("Ignoring linked list DMA on non-GPU port: {}", port as u8);
+            self.dma.channel_mut(port).done();
+            return;
         }
 
+        let transfer = self.next_linked_list_transfer();
+
+        let mut packets = 0;
+        let mut words = 0;
+
         loop {
+            let visit = &mut self.linked_list_visits[(addr >> 2) as usize];
+
+            let revisited = *visit == transfer;
+
+            *visit = transfer;
+
+            if revisited {
+                panic!("DMA linked list loop: node {:08x} revisited after {} packets \
+                        ({} words), list base {:08x}", addr, packets, words, base);
+            }
+
+            if packets >= max_packets {
+                panic!("DMA linked list exceeded {} packets ({} words), list base {:08x}, \
+                        last node {:08x}", max_packets, words, base, addr);
+            }
+
             let header = self.ram.load32(addr);
 
             let mut remsz = header >> 24;
 
+            if words + remsz > max_words {
+                panic!("DMA linked list exceeded {} words after {} packets, list base {:08x}, \
+                        last node {:08x}", max_words, packets, base, addr);
+            }
+
+            packets += 1;
+            words += remsz;
+
+            self.gpu.count_dma_packet();
+
+            // The CPU is stalled while the DMA walks the list.
+            self.tick(dma::LINKED_LIST_HEADER_CYCLES + remsz * dma::WORD_CYCLES);
+
             while remsz > 0 {
                 addr = (addr + 4) & 0x1ffffc;
 
                 let command = self.ram.load32(addr);
 
-                self.gpu.gp0(command);
+                self.dma_gp0(command);
 
                 remsz -= 1;
             }
 
+            // The hardware only looks at bit 23 of the next pointer, the
+            // usual 0xffffff terminator is just a convention.
             if header & 0x800000 != 0 {
                 break;
             }
 
+            // Pointers past the end of RAM wrap around the 2MB mirror.
             addr = header & 0x1ffffc;
         }
 
-        channel.done();
+        self.dma.channel_mut(port).done();
+    }
+
+    /// Stamp marking the nodes visited by a new linked list transfer.
+    fn next_linked_list_transfer(&mut self) -> u32 {
+        self.linked_list_transfer = self.linked_list_transfer.wrapping_add(1);
+
+        // Stale stamps could match again once the counter wraps.
+        if self.linked_list_transfer == 0 {
+            for visit in self.linked_list_visits.iter_mut() {
+                *visit = 0;
+            }
+
+            self.linked_list_transfer = 1;
+        }
+
+        self.linked_list_transfer
+    }
+
+    /// GPU DMA holds off while the GP0 FIFO is full, the rest of the console
+    /// keeps running in the meantime.
+    fn dma_gp0(&mut self, value: u32) {
+        while self.gpu.gp0_fifo_full() {
+            let cycles = self.gpu.busy_cpu_cycles();
+
+            self.tick(cycles);
+        }
+
+        self.gpu.gp0_dma(value);
+
+        self.check_gpu_irq();
     }
 
     fn do_dma_block(&mut self, port: Port) {
-        let channel = self.dma.channel_mut(port);
+        let channel = *self.dma.channel(port);
 
         let increment: i32 = match channel.step() {
             Step::Increment => 4,
@@ -402,7 +532,7 @@ impl Interconnect {
                     let source_word = self.ram.load32(current_address);
 
                     match port {
-                        Port::GPU => self.gpu.gp0(source_word),
+                        Port::GPU => self.dma_gp0(source_word),
                         _ => panic!("Unhandled DMA destination port {}", port as u8),
                     }
                 },
@@ -412,6 +542,7 @@ impl Interconnect {
                             1 => 0xffffff,
                             _ => addr.wrapping_sub(4) & 0x1fffff,
                         },
+                        Port::GPU => self.gpu.read(),
                         _ => panic!("Unhandled DMA src port {}", port as u8),
                     };
 
@@ -428,6 +559,47 @@ impl Interconnect {
             remsz -= 1;
         }
 
-        channel.done();
+        self.dma.channel_mut(port).done();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interconnect;
+    use bios::Bios;
+
+    const GP0: u32 = 0x1f801810;
+    const GPU_DMA_BASE: u32 = 0x1f8010a0;
+    const GPU_DMA_BLOCK: u32 = 0x1f8010a4;
+    const GPU_DMA_CONTROL: u32 = 0x1f8010a8;
+
+    #[test]
+    fn gpu_dma_to_ram() {
+        let mut inter = Interconnect::new(Bios::from_data(vec![0; 512 * 1024]));
+
+        // 3x1 pixels at (100, 7) then read them back.
+        for &word in &[0xa0000000, 0x00070064, 0x00010003, 0x00020001, 0x00000003,
+                       0xc0000000, 0x00070064, 0x00010003] {
+            inter.store32(GP0, word);
+        }
+
+        // Let the GPU take the commands out of its FIFO.
+        inter.tick(10000);
+
+        // Marker after the transfer, it must survive.
+        inter.store32(0x1008, 0xdeadbeef);
+
+        // Two blocks of one word, to RAM, incrementing, sync mode 1.
+        inter.store32(GPU_DMA_BASE, 0x1000);
+        inter.store32(GPU_DMA_BLOCK, 0x00020001);
+        inter.store32(GPU_DMA_CONTROL, 0x01000200);
+
+        assert_eq!(inter.load32(0x1000), 0x00020001);
+        assert_eq!(inter.load32(0x1004), 0x00000003);
+        assert_eq!(inter.load32(0x1008), 0xdeadbeef);
+
+        // Channel done, GPUSTAT bit 27 clear.
+        assert!(inter.load32(GPU_DMA_CONTROL) & (1 << 24) == 0);
+        assert!(inter.load32(0x1f801814) & (1 << 27) == 0);
+    }
+}
@@ -0,0 +1,50 @@
+#[derive(Clone, Copy)]
+pub enum Interrupt {
+    VBlank = 0,
+    /// GP0 0x1F
+    Gpu = 1,
+    Timer0 = 4,
+    Timer1 = 5,
+    Timer2 = 6,
+}
+
+/// The I_STAT and I_MASK registers.
+pub struct InterruptState {
+    status: u16,
+    mask: u16,
+}
+
+impl InterruptState {
+    pub fn new() -> InterruptState {
+        InterruptState {
+            status: 0,
+            mask: 0,
+        }
+    }
+
+    /// Whether the interrupt line going to the CPU is asserted.
+    pub fn active(&self) -> bool {
+        self.status & self.mask != 0
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Writing to I_STAT acknowledges the interrupts whose bit is 0.
+    pub fn ack(&mut self, value: u16) {
+        self.status &= value;
+    }
+
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    pub fn set_mask(&mut self, value: u16) {
+        self.mask = value & 0x7ff;
+    }
+
+    pub fn assert(&mut self, which: Interrupt) {
+        self.status |= 1 << (which as usize);
+    }
+}
@@ -1,4 +1,6 @@
 use std::env::args;
+use std::path::PathBuf;
+use std::process;
 
 mod bios;
 mod interconnect;
@@ -8,10 +10,24 @@ mod ram;
 mod dma;
 mod channel;
 mod gpu;
+mod vram;
+mod rasterizer;
+mod renderer;
+mod tiles;
+mod texture_cache;
+mod texture_pack;
+mod irq;
+mod timers;
+mod gpu_dump;
+mod image;
+mod debug_view;
+mod gpu_stats;
 
 use bios::*;
 use interconnect::*;
 use cpu::*;
+use gpu::{Deinterlacing, Gpu, VRamView};
+use rasterizer::TextureDepth;
 
 //TODO: Check SW instruction.
 //TODO: Check RAM.
@@ -20,13 +36,329 @@ use cpu::*;
 fn main() {
     let bios_file = args().nth(1).unwrap();
 
+    if bios_file == "replay" {
+        return replay(&args().nth(2).unwrap());
+    }
+
+    let mut capture = Capture::new();
+    let mut textures = TexturePackOptions::new();
+
     let bios = Bios::new(&bios_file).unwrap();
 
-    let inter = Interconnect::new(bios);
+    let mut inter = Interconnect::new(bios);
+
+    let (mut max_packets, mut max_words) = (dma::LINKED_LIST_MAX_PACKETS, dma::LINKED_LIST_MAX_WORDS);
+
+    let mut record_path = None;
+    let mut record_frame = None;
+    let mut frame_limit: Option<u32> = None;
+    let mut report_stats = false;
+
+    for arg in args().skip(2) {
+        if let Some(n) = arg.strip_prefix("--dma-max-packets=") {
+            max_packets = n.parse().unwrap();
+        } else if let Some(n) = arg.strip_prefix("--dma-max-words=") {
+            max_words = n.parse().unwrap();
+        } else if let Some(path) = arg.strip_prefix("--record-gpu=") {
+            record_path = Some(PathBuf::from(path));
+        } else if let Some(n) = arg.strip_prefix("--record-gpu-frame=") {
+            record_frame = Some(n.parse().unwrap());
+        } else if let Some(n) = arg.strip_prefix("--scale=") {
+            inter.gpu_mut().set_internal_resolution(n.parse().unwrap());
+        } else if arg == "--gpu-thread" {
+            inter.gpu_mut().set_threaded_rendering(true);
+        } else if let Some(n) = arg.strip_prefix("--gpu-tiles=") {
+            inter.gpu_mut().set_tile_threads(n.parse().unwrap());
+        } else if arg == "--no-dither" {
+            inter.gpu_mut().set_dithering_allowed(false);
+        } else if arg == "--texture-cache" {
+            inter.gpu_mut().set_texture_cache(true);
+        } else if let Some(mode) = arg.strip_prefix("--deinterlace=") {
+            inter.gpu_mut().set_deinterlacing(parse_deinterlacing(mode));
+        } else if let Some(n) = arg.strip_prefix("--frames=") {
+            frame_limit = Some(n.parse().unwrap());
+        } else if arg == "--gpu-stats" {
+            report_stats = true;
+        } else if let Some(dir) = arg.strip_prefix("--debug-dir=") {
+            inter.gpu_mut().set_debug_output(PathBuf::from(dir));
+        } else if !capture.parse_option(&arg) && !textures.parse_option(&arg) {
+            panic!("Unknown option: {}", arg);
+        }
+    }
+
+    inter.set_linked_list_limits(max_packets, max_words);
+
+    textures.apply(inter.gpu_mut());
+
+    // With a frame number only that frame is recorded, otherwise everything
+    // from power on.
+    if let Some(path) = record_path {
+        match record_frame {
+            Some(frame) => inter.gpu_mut().record_to(path, frame, Some(1)),
+            None => inter.gpu_mut().record_to(path, 0, None),
+        }
+    }
 
     let mut cpu = Cpu::new(inter);
 
+    // Capturing stops the emulator so that scripts can compare the images.
+    // The BIOS takes a while to show anything so there's no good default.
+    let capture_frame = match capture.is_requested() {
+        true => match capture.frame {
+            Some(frame) => Some(frame),
+            None => panic!("--screenshot and --vram-dump need --capture-frame"),
+        },
+        false => None,
+    };
+
+    if report_stats && frame_limit.is_none() && capture_frame.is_none() {
+        panic!("--gpu-stats needs --frames or --capture-frame");
+    }
+
+    // Stops after `--frames` or the captured frame, whichever comes first.
+    let last_frame = match (frame_limit, capture_frame) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
     loop {
         cpu.run_next_instruction();
+
+        if let Some(frame) = last_frame {
+            if cpu.interconnect_mut().gpu_mut().frame() >= frame {
+                break;
+            }
+        }
+    }
+
+    let gpu = cpu.interconnect_mut().gpu_mut();
+
+    if capture_frame.is_some_and(|frame| gpu.frame() >= frame) {
+        capture.save(gpu);
+    }
+
+    if report_stats {
+        print!("{}", gpu.stats_report());
+    }
+}
+
+/// Plays back a GPU dump made with `--record-gpu`, no BIOS needed. Images
+/// are captured at the end of `--capture-frame` or of the whole dump.
+fn replay(path: &str) {
+    let mut capture = Capture::new();
+    let mut textures = TexturePackOptions::new();
+    let mut frame_stats = false;
+
+    let mut gpu = Gpu::new();
+
+    for arg in args().skip(3) {
+        if let Some(n) = arg.strip_prefix("--compare-tiles=") {
+            return compare_tiles(path, n.parse().unwrap());
+        } else if let Some(n) = arg.strip_prefix("--scale=") {
+            gpu.set_internal_resolution(n.parse().unwrap());
+        } else if arg == "--gpu-thread" {
+            gpu.set_threaded_rendering(true);
+        } else if let Some(n) = arg.strip_prefix("--gpu-tiles=") {
+            gpu.set_tile_threads(n.parse().unwrap());
+        } else if arg == "--texture-cache" {
+            gpu.set_texture_cache(true);
+        } else if let Some(mode) = arg.strip_prefix("--deinterlace=") {
+            gpu.set_deinterlacing(parse_deinterlacing(mode));
+        } else if arg == "--gpu-stats" {
+            frame_stats = true;
+        } else if let Some(dir) = arg.strip_prefix("--debug-dir=") {
+            gpu.set_debug_output(PathBuf::from(dir));
+        } else if !capture.parse_option(&arg) && !textures.parse_option(&arg) {
+            panic!("Unknown option: {}", arg);
+        }
+    }
+
+    textures.apply(&mut gpu);
+
+    let mut frames = 0;
+
+    let result = gpu_dump::replay(path, gpu, |gpu, frame| {
+        if frame_stats {
+            let stats = gpu.frame_stats();
+
+            println!("Frame {}: {} primitives, {} pixels, {} texels",
+                     frame,
+                     stats.primitives(),
+                     stats.pixels,
+                     stats.texels);
+        }
+
+        if capture.frame == Some(frame) {
+            capture.save(gpu);
+        }
+
+        frames += 1;
+    });
+
+    match result {
+        Ok(mut gpu) => {
+            println!("Replayed {} frames, GPUSTAT {:08x}", frames, gpu.status());
+
+            if capture.frame.is_none() {
+                capture.save(&mut gpu);
+            }
+
+            if frame_stats {
+                print!("{}", gpu.stats_report());
+            }
+        },
+        Err(e) => panic!("Can't replay GPU dump {}: {}", path, e),
+    }
+}
+
+/// Checks that the tile-parallel rasterizer draws exactly like the scalar
+/// one over a recorded dump.
+fn compare_tiles(path: &str, threads: usize) {
+    match gpu_dump::compare_tiles(path, threads) {
+        Ok(None) => println!("Tiled rendering matches"),
+        Ok(Some(frame)) => {
+            println!("Tiled rendering differs from frame {}", frame);
+            process::exit(1);
+        },
+        Err(e) => panic!("Can't replay GPU dump {}: {}", path, e),
+    }
+}
+
+/// Screenshot and VRAM images requested on the command line.
+struct Capture {
+    screenshot: Option<PathBuf>,
+    vram: Option<PathBuf>,
+    view: VRamView,
+    frame: Option<u32>,
+}
+
+impl Capture {
+    fn new() -> Capture {
+        Capture {
+            screenshot: None,
+            vram: None,
+            view: VRamView::Bits15,
+            frame: None,
+        }
+    }
+
+    /// Returns false if `arg` isn't a capture option.
+    fn parse_option(&mut self, arg: &str) -> bool {
+        if let Some(path) = arg.strip_prefix("--screenshot=") {
+            self.screenshot = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("--vram-dump=") {
+            self.vram = Some(PathBuf::from(path));
+        } else if let Some(view) = arg.strip_prefix("--vram-view=") {
+            self.view = parse_vram_view(view);
+        } else if let Some(n) = arg.strip_prefix("--capture-frame=") {
+            self.frame = Some(n.parse().unwrap());
+        } else {
+            return false;
+        }
+
+        true
+    }
+
+    fn is_requested(&self) -> bool {
+        self.screenshot.is_some() || self.vram.is_some()
+    }
+
+    fn save(&self, gpu: &mut Gpu) {
+        if let Some(ref path) = self.screenshot {
+            gpu.display_frame().save(path).unwrap();
+        }
+
+        if let Some(ref path) = self.vram {
+            gpu.vram_frame(self.view).save(path).unwrap();
+        }
+    }
+}
+
+/// Texture dumping and replacement requested on the command line, both use
+/// the same directory.
+struct TexturePackOptions {
+    dir: Option<PathBuf>,
+    dump: bool,
+    replace: bool,
+}
+
+impl TexturePackOptions {
+    fn new() -> TexturePackOptions {
+        TexturePackOptions {
+            dir: None,
+            dump: false,
+            replace: false,
+        }
+    }
+
+    /// Returns false if `arg` isn't a texture pack option.
+    fn parse_option(&mut self, arg: &str) -> bool {
+        let dir = if let Some(dir) = arg.strip_prefix("--dump-textures=") {
+            self.dump = true;
+            dir
+        } else if let Some(dir) = arg.strip_prefix("--replace-textures=") {
+            self.replace = true;
+            dir
+        } else {
+            return false;
+        };
+
+        let dir = PathBuf::from(dir);
+
+        if self.dir.as_ref().is_some_and(|d| *d != dir) {
+            panic!("Texture dumping and replacement must use the same directory");
+        }
+
+        self.dir = Some(dir);
+
+        true
+    }
+
+    fn apply(&self, gpu: &mut Gpu) {
+        if let Some(ref dir) = self.dir {
+            gpu.set_texture_pack(dir.clone(), self.dump, self.replace);
+        }
+    }
+}
+
+fn parse_deinterlacing(mode: &str) -> Deinterlacing {
+    match mode {
+        "weave" => Deinterlacing::Weave,
+        "bob" => Deinterlacing::Bob,
+        "blend" => Deinterlacing::Blend,
+        _ => panic!("Invalid deinterlacing mode: {}", mode),
+    }
+}
+
+/// `15`, `24` or a texture page as `DEPTH,PAGE_X,PAGE_Y,CLUT_X,CLUT_Y` with
+/// a depth of 4, 8 or 15 and coordinates in VRAM pixels.
+fn parse_vram_view(view: &str) -> VRamView {
+    match view {
+        "15" => return VRamView::Bits15,
+        "24" => return VRamView::Bits24,
+        _ => (),
+    }
+
+    let fields: Vec<&str> = view.split(',').collect();
+
+    if fields.len() != 5 {
+        panic!("Invalid VRAM view: {}", view);
+    }
+
+    let depth = match fields[0] {
+        "4" => TextureDepth::T4Bit,
+        "8" => TextureDepth::T8Bit,
+        "15" => TextureDepth::T15Bit,
+        _ => panic!("Invalid texture depth: {}", fields[0]),
+    };
+
+    let coord = |i: usize| fields[i].parse::<u16>().unwrap();
+
+    VRamView::Texture {
+        page_x: coord(1),
+        page_y: coord(2),
+        depth,
+        clut_x: coord(3),
+        clut_y: coord(4),
     }
 }
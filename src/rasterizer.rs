@@ -0,0 +1,1331 @@
+use texture_pack::Replacement;
+use vram::Surface;
+
+/// 24bit colour as sent in GP0 commands.
+#[derive(Clone, Copy)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub fn from_command(value: u32) -> Color {
+        Color {
+            r: value as u8,
+            g: (value >> 8) as u8,
+            b: (value >> 16) as u8,
+        }
+    }
+
+    pub fn to_15bit(self) -> u16 {
+        let r = (self.r >> 3) as u16;
+        let g = (self.g >> 3) as u16;
+        let b = (self.b >> 3) as u16;
+
+        r | (g << 5) | (b << 10)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Vertex {
+    pub x: i32,
+    pub y: i32,
+    pub color: Color,
+    pub u: u8,
+    pub v: u8,
+}
+
+impl Vertex {
+    /// Decodes a GP0 vertex word and applies the drawing offset. Coordinates
+    /// are signed 11bit values.
+    pub fn from_command(value: u32, color: Color, x_offset: i16, y_offset: i16) -> Vertex {
+        let x = (((value << 21) as i32) >> 21) + x_offset as i32;
+        let y = (((value << 5) as i32) >> 21) + y_offset as i32;
+
+        Vertex { x, y, color, u: 0, v: 0 }
+    }
+
+    /// Sets the texture coordinates from the low half of a GP0 texcoord
+    /// word.
+    pub fn set_texcoord(&mut self, value: u32) {
+        self.u = value as u8;
+        self.v = (value >> 8) as u8;
+    }
+
+    pub fn upscaled(self, scale: i32) -> Vertex {
+        Vertex {
+            x: self.x * scale,
+            y: self.y * scale,
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum TextureDepth {
+    T4Bit = 0,
+    T8Bit = 1,
+    T15Bit = 2,
+}
+
+impl TextureDepth {
+    pub fn from_field(value: u32) -> TextureDepth {
+        match value & 3 {
+            0 => TextureDepth::T4Bit,
+            1 => TextureDepth::T8Bit,
+            // 3 is reserved and behaves like 15bit.
+            _ => TextureDepth::T15Bit,
+        }
+    }
+}
+
+/// Texture window set by GP0 0xE2, in units of 8 texels.
+#[derive(Clone, Copy)]
+pub struct TextureWindow {
+    pub x_mask: u8,
+    pub y_mask: u8,
+    pub x_offset: u8,
+    pub y_offset: u8,
+}
+
+impl TextureWindow {
+    pub fn apply(self, u: u8, v: u8) -> (u8, u8) {
+        let u = (u & !(self.x_mask << 3)) | ((self.x_offset & self.x_mask) << 3);
+        let v = (v & !(self.y_mask << 3)) | ((self.y_offset & self.y_mask) << 3);
+
+        (u, v)
+    }
+}
+
+/// Where and how a primitive fetches its texels.
+#[derive(Clone, Copy)]
+pub struct Texture {
+    pub page_x: u16,
+    pub page_y: u16,
+    pub depth: TextureDepth,
+    pub clut_x: u16,
+    pub clut_y: u16,
+    pub window: TextureWindow,
+    /// Raw textures are drawn as is, otherwise texels are modulated by the
+    /// vertex colour.
+    pub raw: bool,
+    /// Image from the texture pack drawn instead of the texels.
+    pub replacement: Option<Replacement>,
+}
+
+impl Texture {
+    /// Decodes the CLUT attribute found in the high half of a polygon's
+    /// first texcoord word.
+    pub fn clut_from_attribute(value: u32) -> (u16, u16) {
+        let x = ((value & 0x3f) * 16) as u16;
+        let y = ((value >> 6) & 0x1ff) as u16;
+
+        (x, y)
+    }
+
+    pub fn fetch<S: Surface>(&self, vram: &S, u: u8, v: u8) -> u16 {
+        let (x, y, u) = self.address(u, v);
+
+        let word = vram.load16(x, y);
+
+        match self.palette_index(word, u) {
+            Some(index) => vram.load16(self.clut_x + index, self.clut_y),
+            None => word,
+        }
+    }
+
+    /// VRAM halfword holding texel (u, v) once the texture window is
+    /// applied, along with the windowed u.
+    pub fn address(&self, u: u8, v: u8) -> (u16, u16, u16) {
+        let (u, v) = self.window.apply(u, v);
+
+        let u = u as u16;
+        let y = self.page_y + v as u16;
+
+        let x = match self.depth {
+            TextureDepth::T4Bit => self.page_x + u / 4,
+            TextureDepth::T8Bit => self.page_x + u / 2,
+            TextureDepth::T15Bit => self.page_x + u,
+        };
+
+        (x, y, u)
+    }
+
+    /// CLUT entry of texel `u` packed in `word`, `None` for 15bit textures
+    /// which hold the colour directly.
+    pub fn palette_index(&self, word: u16, u: u16) -> Option<u16> {
+        match self.depth {
+            TextureDepth::T4Bit => Some((word >> ((u & 3) * 4)) & 0xf),
+            TextureDepth::T8Bit => Some((word >> ((u & 1) * 8)) & 0xff),
+            TextureDepth::T15Bit => None,
+        }
+    }
+
+    /// VRAM areas the texels and CLUT are read from.
+    pub fn vram_areas(&self) -> Vec<DrawArea> {
+        let (page_width, clut_width) = match self.depth {
+            TextureDepth::T4Bit => (64, 16),
+            TextureDepth::T8Bit => (128, 256),
+            TextureDepth::T15Bit => (256, 0),
+        };
+
+        let mut areas = vec![wrapped_area(self.page_x, self.page_y, page_width, 256)];
+
+        if clut_width > 0 {
+            areas.push(wrapped_area(self.clut_x, self.clut_y, clut_width, 1));
+        }
+
+        areas
+    }
+}
+
+/// Area covering a block of VRAM, the whole width when it wraps around the
+/// right edge.
+fn wrapped_area(x: u16, y: u16, width: i32, height: i32) -> DrawArea {
+    let (left, right) = match x as i32 + width > 1024 {
+        true => (0, 1023),
+        false => (x as i32, x as i32 + width - 1),
+    };
+
+    DrawArea {
+        left,
+        top: y as i32,
+        right,
+        bottom: (y as i32 + height - 1).min(511),
+    }
+}
+
+/// Modulates a 15bit texel by a vertex colour, 0x80 being the neutral
+/// intensity. The result keeps 8 bits of precision for dithering.
+fn modulate(texel: u16, color: Color) -> Color {
+    let channel = |shift: u16, c: u8| {
+        let t = ((texel >> shift) & 0x1f) as u32;
+
+        ((t * c as u32) >> 4).min(0xff) as u8
+    };
+
+    Color {
+        r: channel(0, color.r),
+        g: channel(5, color.g),
+        b: channel(10, color.b),
+    }
+}
+
+/// Inclusive clipping rectangle set by GP0 0xE3 and 0xE4.
+#[derive(Clone, Copy)]
+pub struct DrawArea {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl DrawArea {
+    pub fn intersection(&self, other: &DrawArea) -> Option<DrawArea> {
+        let area = DrawArea {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        };
+
+        match area.left <= area.right && area.top <= area.bottom {
+            true => Some(area),
+            false => None,
+        }
+    }
+
+    /// Smallest area containing both.
+    pub fn union(&self, other: &DrawArea) -> DrawArea {
+        DrawArea {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+}
+
+/// Semi-transparency equations, B being the background pixel already in
+/// VRAM and F the pixel being drawn.
+#[derive(Clone, Copy, Debug)]
+pub enum SemiTransparency {
+    /// B/2 + F/2
+    Average = 0,
+    /// B + F
+    Add = 1,
+    /// B - F
+    Subtract = 2,
+    /// B + F/4
+    AddQuarter = 3,
+}
+
+impl SemiTransparency {
+    pub fn from_field(value: u8) -> SemiTransparency {
+        match value & 3 {
+            0 => SemiTransparency::Average,
+            1 => SemiTransparency::Add,
+            2 => SemiTransparency::Subtract,
+            _ => SemiTransparency::AddQuarter,
+        }
+    }
+
+    fn blend(self, back: u16, front: u16) -> u16 {
+        let channel = |shift: u16| {
+            let b = ((back >> shift) & 0x1f) as i32;
+            let f = ((front >> shift) & 0x1f) as i32;
+
+            let c = match self {
+                SemiTransparency::Average => (b + f) >> 1,
+                SemiTransparency::Add => b + f,
+                SemiTransparency::Subtract => b - f,
+                SemiTransparency::AddQuarter => b + (f >> 2),
+            };
+
+            (c.clamp(0, 0x1f) as u16) << shift
+        };
+
+        channel(0) | channel(5) | channel(10) | (front & 0x8000)
+    }
+}
+
+/// Drawing environment a primitive is rendered with.
+#[derive(Clone, Copy)]
+pub struct DrawState {
+    pub area: DrawArea,
+    /// Blending equation, `None` for opaque primitives.
+    pub semi_transparency: Option<SemiTransparency>,
+    /// Set bit 15 of every pixel written.
+    pub set_mask: bool,
+    /// Leave pixels with bit 15 set untouched.
+    pub check_mask: bool,
+    /// Dither the 24bit colour when converting it to 15bit.
+    pub dither: bool,
+    /// Resolution multiplier of the pass, primitives are drawn to the native
+    /// VRAM at 1 and to the upscaled copy otherwise.
+    pub scale: i32,
+    /// Parity of the VRAM rows left untouched, the ones of the displayed
+    /// field in 480 line interlaced mode.
+    pub skipped_lines: Option<i32>,
+}
+
+impl DrawState {
+    /// State for the pass drawing into the upscaled VRAM. The drawing area
+    /// covers whole upscaled blocks.
+    pub fn upscaled(&self, scale: i32) -> DrawState {
+        let area = self.area;
+
+        DrawState {
+            area: DrawArea {
+                left: area.left * scale,
+                top: area.top * scale,
+                right: area.right * scale + scale - 1,
+                bottom: area.bottom * scale + scale - 1,
+            },
+            scale,
+            ..*self
+        }
+    }
+
+    /// True if nothing may be drawn on row `y` of the pass.
+    pub fn skips_line(&self, y: i32) -> bool {
+        match self.skipped_lines {
+            Some(parity) => (y / self.scale) & 1 == parity,
+            None => false,
+        }
+    }
+}
+
+/// The GPU's 4x4 ordered dither offsets, added to the 8bit colour before
+/// it's truncated to 5 bits.
+pub const DITHER_MATRIX: [[i32; 4]; 4] = [
+    [-4,  0, -3,  1],
+    [ 2, -2,  3, -1],
+    [-3,  1, -4,  0],
+    [ 3, -1,  2, -2],
+];
+
+/// Converts a 24bit colour to 15bit, dithering it if requested.
+fn encode(state: &DrawState, x: i32, y: i32, color: Color) -> u16 {
+    if !state.dither {
+        return color.to_15bit();
+    }
+
+    // Upscaled pixels reuse the pattern of the native pixel they belong to.
+    let (x, y) = (x / state.scale, y / state.scale);
+
+    let offset = DITHER_MATRIX[(y & 3) as usize][(x & 3) as usize];
+
+    let channel = |c: u8| ((c as i32 + offset).clamp(0, 0xff) >> 3) as u16;
+
+    channel(color.r) | (channel(color.g) << 5) | (channel(color.b) << 10)
+}
+
+/// Work done by the GPU to draw a primitive.
+#[derive(Clone, Copy, Default)]
+pub struct DrawStats {
+    /// Pixels written to VRAM.
+    pub pixels: u32,
+    /// Texels fetched from VRAM.
+    pub texels: u32,
+}
+
+impl ::std::ops::AddAssign for DrawStats {
+    fn add_assign(&mut self, other: DrawStats) {
+        self.pixels += other.pixels;
+        self.texels += other.texels;
+    }
+}
+
+/// Shades a single pixel and writes it to VRAM. `texcoord` is in 8.12
+/// fixed point and ignored for untextured primitives.
+fn draw_pixel<S: Surface>(vram: &mut S,
+                          state: &DrawState,
+                          texture: Option<Texture>,
+                          x: i32,
+                          y: i32,
+                          color: Color,
+                          texcoord: (i32, i32)) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    if state.skips_line(y) {
+        return stats;
+    }
+
+    // Untextured primitives blend every pixel, textured ones only the texels
+    // with their STP bit set.
+    let (pixel, blend) = match texture {
+        Some(texture) => {
+            let (u, v) = ((texcoord.0 >> 12) as u8, (texcoord.1 >> 12) as u8);
+
+            let texel = vram.fetch_texel(&texture, u, v);
+
+            // Replacements are only drawn in the pass that gets displayed.
+            let texel = match texture.replacement {
+                Some(ref r) if state.scale == vram.scale() as i32 => vram.fetch_replacement(r, texel, texcoord),
+                _ => texel,
+            };
+
+            stats.texels += 1;
+
+            // Fully black texels are transparent.
+            if texel == 0 {
+                return stats;
+            }
+
+            let pixel = match texture.raw {
+                true => texel,
+                false => encode(state, x, y, modulate(texel, color)) | (texel & 0x8000),
+            };
+
+            (pixel, texel & 0x8000 != 0)
+        },
+        None => (encode(state, x, y, color), true),
+    };
+
+    if put_pixel(vram, state, x as u16, y as u16, pixel, blend) {
+        stats.pixels += 1;
+    }
+
+    stats
+}
+
+/// Writes a shaded pixel to VRAM, applying blending and the mask bit rules.
+/// Returns false if the pixel was protected by the mask.
+fn put_pixel<S: Surface>(vram: &mut S,
+                         state: &DrawState,
+                         x: u16,
+                         y: u16,
+                         pixel: u16,
+                         blend: bool) -> bool {
+    let back = match state.scale {
+        1 => vram.load16(x, y),
+        _ => vram.load_upscaled(x, y),
+    };
+
+    if state.check_mask && back & 0x8000 != 0 {
+        return false;
+    }
+
+    let pixel = match state.semi_transparency {
+        Some(mode) if blend => mode.blend(back, pixel),
+        _ => pixel,
+    };
+
+    let mask = (state.set_mask as u16) << 15;
+
+    match state.scale {
+        1 => vram.store16(x, y, pixel | mask),
+        _ => vram.store_upscaled(x, y, pixel | mask),
+    }
+
+    true
+}
+
+/// Draws a quad the way the GPU does: as the triangles 0-1-2 and 1-2-3.
+pub fn draw_quad<S: Surface>(vram: &mut S,
+                             state: &DrawState,
+                             v: [Vertex; 4],
+                             texture: Option<Texture>) -> DrawStats {
+    let mut stats = draw_triangle(vram, state, [v[0], v[1], v[2]], texture);
+
+    stats += draw_triangle(vram, state, [v[1], v[2], v[3]], texture);
+
+    stats
+}
+
+/// Only the native pass counts towards the returned stats, the upscaled one
+/// doesn't exist on the real hardware.
+pub fn draw_triangle<S: Surface>(vram: &mut S,
+                                 state: &DrawState,
+                                 v: [Vertex; 3],
+                                 texture: Option<Texture>) -> DrawStats {
+    let stats = rasterize_triangle(vram, state, v, texture);
+
+    let scale = vram.scale() as i32;
+
+    if scale > 1 {
+        let v = [v[0].upscaled(scale), v[1].upscaled(scale), v[2].upscaled(scale)];
+
+        rasterize_triangle(vram, &state.upscaled(scale), v, texture);
+    }
+
+    stats
+}
+
+fn rasterize_triangle<S: Surface>(vram: &mut S,
+                                  state: &DrawState,
+                                  v: [Vertex; 3],
+                                  texture: Option<Texture>) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    let t = match TriangleSetup::new(state, v) {
+        Some(t) => t,
+        None => return stats,
+    };
+
+    for y in t.y_start..=t.y_end {
+        for x in t.x_start..=t.x_end {
+            if !t.inside(x, y) {
+                continue;
+            }
+
+            let color = Color {
+                r: t.r.at(x, y) as u8,
+                g: t.g.at(x, y) as u8,
+                b: t.b.at(x, y) as u8,
+            };
+
+            let texcoord = (t.u.at_fixed(x, y), t.v.at_fixed(x, y));
+
+            stats += draw_pixel(vram, state, texture, x, y, color, texcoord);
+        }
+    }
+
+    stats
+}
+
+/// Everything needed to walk the pixels of a triangle, shared by the pixel
+/// and span rasterizers.
+pub struct TriangleSetup {
+    /// Vertices in counter-clockwise order.
+    vertices: [Vertex; 3],
+    /// Whether pixels exactly on each edge, in `edges` order, are drawn.
+    pub top_left: [bool; 3],
+
+    pub r: Gradient,
+    pub g: Gradient,
+    pub b: Gradient,
+    pub u: Gradient,
+    pub v: Gradient,
+
+    /// Bounding box clipped to the drawing area, inclusive.
+    pub x_start: i32,
+    pub x_end: i32,
+    pub y_start: i32,
+    pub y_end: i32,
+}
+
+impl TriangleSetup {
+    /// Returns `None` for the triangles the GPU doesn't draw.
+    pub fn new(state: &DrawState, v: [Vertex; 3]) -> Option<TriangleSetup> {
+        let min_x = v[0].x.min(v[1].x).min(v[2].x);
+        let max_x = v[0].x.max(v[1].x).max(v[2].x);
+        let min_y = v[0].y.min(v[1].y).min(v[2].y);
+        let max_y = v[0].y.max(v[1].y).max(v[2].y);
+
+        // The GPU silently drops polygons that are too large.
+        if max_x - min_x >= 1024 * state.scale || max_y - min_y >= 512 * state.scale {
+            return None;
+        }
+
+        let mut v = v;
+
+        let mut twice_area = edge(v[0], v[1], v[2]);
+
+        if twice_area == 0 {
+            return None;
+        }
+
+        // Make the winding consistent so that the edge functions are
+        // positive inside the triangle.
+        if twice_area < 0 {
+            v.swap(1, 2);
+            twice_area = -twice_area;
+        }
+
+        let area = state.area;
+
+        Some(TriangleSetup {
+            vertices: v,
+            top_left: [
+                is_top_left(v[1], v[2]),
+                is_top_left(v[2], v[0]),
+                is_top_left(v[0], v[1]),
+            ],
+
+            r: Gradient::new(v, twice_area, |v| v.color.r as i32),
+            g: Gradient::new(v, twice_area, |v| v.color.g as i32),
+            b: Gradient::new(v, twice_area, |v| v.color.b as i32),
+            u: Gradient::new(v, twice_area, |v| v.u as i32),
+            v: Gradient::new(v, twice_area, |v| v.v as i32),
+
+            x_start: min_x.max(area.left),
+            x_end: max_x.min(area.right),
+            y_start: min_y.max(area.top),
+            y_end: max_y.min(area.bottom),
+        })
+    }
+
+    pub fn inside(&self, x: i32, y: i32) -> bool {
+        let w = self.edges(x, y);
+
+        (0..3).all(|i| w[i] > 0 || (w[i] == 0 && self.top_left[i]))
+    }
+
+    /// Edge functions at `(x, y)`, positive inside the triangle.
+    pub fn edges(&self, x: i32, y: i32) -> [i32; 3] {
+        let v = self.vertices;
+        let p = (x, y);
+
+        [
+            edge_point(v[1], v[2], p),
+            edge_point(v[2], v[0], p),
+            edge_point(v[0], v[1], p),
+        ]
+    }
+
+    /// Change of the edge functions from one pixel to the next on a row.
+    pub fn edges_dx(&self) -> [i32; 3] {
+        let v = self.vertices;
+
+        [v[1].y - v[2].y, v[2].y - v[0].y, v[0].y - v[1].y]
+    }
+}
+
+/// Draws an axis aligned rectangle with its top-left corner at `v`. The
+/// texture coordinates step by one texel per pixel, backwards along the
+/// flipped axes.
+pub fn draw_rectangle<S: Surface>(vram: &mut S,
+                                  state: &DrawState,
+                                  v: Vertex,
+                                  width: i32,
+                                  height: i32,
+                                  texture: Option<Texture>,
+                                  flip: (bool, bool)) -> DrawStats {
+    let stats = rasterize_rectangle(vram, state, v, (width, height), texture, flip);
+
+    let scale = vram.scale() as i32;
+
+    if scale > 1 {
+        let size = (width * scale, height * scale);
+
+        rasterize_rectangle(vram, &state.upscaled(scale), v.upscaled(scale), size, texture, flip);
+    }
+
+    stats
+}
+
+fn rasterize_rectangle<S: Surface>(vram: &mut S,
+                                   state: &DrawState,
+                                   v: Vertex,
+                                   (width, height): (i32, i32),
+                                   texture: Option<Texture>,
+                                   (flip_x, flip_y): (bool, bool)) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    let area = state.area;
+
+    let x_start = v.x.max(area.left);
+    let x_end = (v.x + width - 1).min(area.right);
+    let y_start = v.y.max(area.top);
+    let y_end = (v.y + height - 1).min(area.bottom);
+
+    // Texture coordinate in 8.12 fixed point of the upscaled pixel `d`
+    // pixels away from the corner.
+    let texcoord = |start: u8, d: i32, flip: bool| {
+        let texel = (d / state.scale) as u8;
+        let fraction = (d % state.scale) * 0x1000 / state.scale;
+
+        match flip {
+            true => ((start.wrapping_sub(texel) as i32) << 12) | (0xfff - fraction),
+            false => ((start.wrapping_add(texel) as i32) << 12) | fraction,
+        }
+    };
+
+    for y in y_start..=y_end {
+        let tv = texcoord(v.v, y - v.y, flip_y);
+
+        for x in x_start..=x_end {
+            let tu = texcoord(v.u, x - v.x, flip_x);
+
+            stats += draw_pixel(vram, state, texture, x, y, v.color, (tu, tv));
+        }
+    }
+
+    stats
+}
+
+/// Draws a line from `a` to `b` inclusive, stepping one pixel at a time
+/// along the major axis with the GPU's 32.32 fixed point DDA.
+pub fn draw_line<S: Surface>(vram: &mut S, state: &DrawState, a: Vertex, b: Vertex) -> DrawStats {
+    let stats = rasterize_line(vram, state, a, b);
+
+    let scale = vram.scale() as i32;
+
+    if scale > 1 {
+        let state = state.upscaled(scale);
+
+        let (mut a, mut b) = (a.upscaled(scale), b.upscaled(scale));
+
+        let x_major = (b.x - a.x).abs() >= (b.y - a.y).abs();
+
+        // Draw as many parallel lines as needed to keep the native
+        // thickness. They're offset along the minor axis so they never
+        // overlap, which matters when blending.
+        for _ in 0..scale {
+            rasterize_line(vram, &state, a, b);
+
+            match x_major {
+                true => {
+                    a.y += 1;
+                    b.y += 1;
+                },
+                false => {
+                    a.x += 1;
+                    b.x += 1;
+                },
+            }
+        }
+    }
+
+    stats
+}
+
+fn rasterize_line<S: Surface>(vram: &mut S, state: &DrawState, a: Vertex, b: Vertex) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    let dx = b.x - a.x;
+    let dy = b.y - a.y;
+
+    // Same size limits as for polygons.
+    if dx.abs() >= 1024 * state.scale || dy.abs() >= 512 * state.scale {
+        return stats;
+    }
+
+    let k = dx.abs().max(dy.abs());
+
+    // The GPU always draws from left to right.
+    let (a, b) = match a.x >= b.x && k > 0 {
+        true => (b, a),
+        false => (a, b),
+    };
+
+    let step = |delta: i32| {
+        match k {
+            0 => 0,
+            _ => line_divide((delta as i64) << 32, k),
+        }
+    };
+
+    let step_x = step(b.x - a.x);
+    let step_y = step(b.y - a.y);
+
+    let step_color = |from: u8, to: u8| {
+        match k {
+            0 => 0,
+            _ => ((to as i64 - from as i64) << 12) / k as i64,
+        }
+    };
+
+    let step_r = step_color(a.color.r, b.color.r);
+    let step_g = step_color(a.color.g, b.color.g);
+    let step_b = step_color(a.color.b, b.color.b);
+
+    let mut x = ((a.x as i64) << 32) | (1 << 31);
+    let mut y = ((a.y as i64) << 32) | (1 << 31);
+
+    if step_x < 0 {
+        x -= 1024;
+    }
+
+    if step_y < 0 {
+        y -= 1024;
+    }
+
+    let mut r = ((a.color.r as i64) << 12) + (1 << 11);
+    let mut g = ((a.color.g as i64) << 12) + (1 << 11);
+    let mut bl = ((a.color.b as i64) << 12) + (1 << 11);
+
+    let area = state.area;
+
+    for _ in 0..=k {
+        let px = (x >> 32) as i32;
+        let py = (y >> 32) as i32;
+
+        let inside = px >= area.left && px <= area.right && py >= area.top && py <= area.bottom;
+
+        if inside {
+            let color = Color {
+                r: (r >> 12) as u8,
+                g: (g >> 12) as u8,
+                b: (bl >> 12) as u8,
+            };
+
+            stats += draw_pixel(vram, state, None, px, py, color, (0, 0));
+        }
+
+        x += step_x;
+        y += step_y;
+
+        r += step_r;
+        g += step_g;
+        bl += step_b;
+    }
+
+    stats
+}
+
+/// Expected work for a triangle, from its area clipped to the drawing area
+/// bounding box. Used when the actual count isn't available yet.
+pub fn estimate_triangle(state: &DrawState, v: [Vertex; 3], textured: bool) -> DrawStats {
+    let area = state.area;
+
+    let min_x = v[0].x.min(v[1].x).min(v[2].x);
+    let max_x = v[0].x.max(v[1].x).max(v[2].x);
+    let min_y = v[0].y.min(v[1].y).min(v[2].y);
+    let max_y = v[0].y.max(v[1].y).max(v[2].y);
+
+    if max_x - min_x >= 1024 || max_y - min_y >= 512 {
+        return DrawStats::default();
+    }
+
+    let width = (max_x.min(area.right) - min_x.max(area.left) + 1).max(0);
+    let height = (max_y.min(area.bottom) - min_y.max(area.top) + 1).max(0);
+
+    let pixels = (edge(v[0], v[1], v[2]).abs() / 2).min(width * height) as u32;
+
+    estimate(pixels, textured)
+}
+
+pub fn estimate_rectangle(state: &DrawState,
+                          v: Vertex,
+                          (width, height): (i32, i32),
+                          textured: bool) -> DrawStats {
+    let area = state.area;
+
+    let width = ((v.x + width - 1).min(area.right) - v.x.max(area.left) + 1).max(0);
+    let height = ((v.y + height - 1).min(area.bottom) - v.y.max(area.top) + 1).max(0);
+
+    estimate((width * height) as u32, textured)
+}
+
+pub fn estimate_line(a: Vertex, b: Vertex) -> DrawStats {
+    let dx = (b.x - a.x).abs();
+    let dy = (b.y - a.y).abs();
+
+    if dx >= 1024 || dy >= 512 {
+        return DrawStats::default();
+    }
+
+    estimate((dx.max(dy) + 1) as u32, false)
+}
+
+fn estimate(pixels: u32, textured: bool) -> DrawStats {
+    DrawStats {
+        pixels,
+        texels: if textured { pixels } else { 0 },
+    }
+}
+
+/// Rounds away from zero so that the last step lands on the end point.
+fn line_divide(delta: i64, k: i32) -> i64 {
+    let k = k as i64;
+
+    let delta = match delta {
+        d if d < 0 => d - (k - 1),
+        d if d > 0 => d + (k - 1),
+        d => d,
+    };
+
+    delta / k
+}
+
+fn edge(a: Vertex, b: Vertex, c: Vertex) -> i32 {
+    edge_point(a, b, (c.x, c.y))
+}
+
+fn edge_point(a: Vertex, b: Vertex, (x, y): (i32, i32)) -> i32 {
+    (b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x)
+}
+
+/// Top-left fill rule: pixels exactly on an edge are only drawn for top and
+/// left edges so that triangles sharing an edge don't overlap.
+fn is_top_left(a: Vertex, b: Vertex) -> bool {
+    let dx = b.x - a.x;
+    let dy = b.y - a.y;
+
+    (dy == 0 && dx > 0) || dy < 0
+}
+
+/// Per-triangle screen-space gradient of an attribute, in the same 12bit
+/// fixed point the GPU uses for its interpolators.
+#[derive(Clone, Copy)]
+pub struct Gradient {
+    origin_x: i32,
+    origin_y: i32,
+    base: i64,
+    dx: i64,
+    dy: i64,
+}
+
+impl Gradient {
+    fn new<F: Fn(&Vertex) -> i32>(v: [Vertex; 3], twice_area: i32, attr: F) -> Gradient {
+        let a0 = attr(&v[0]) as i64;
+        let a1 = attr(&v[1]) as i64 - a0;
+        let a2 = attr(&v[2]) as i64 - a0;
+
+        let dx1 = (v[1].x - v[0].x) as i64;
+        let dy1 = (v[1].y - v[0].y) as i64;
+        let dx2 = (v[2].x - v[0].x) as i64;
+        let dy2 = (v[2].y - v[0].y) as i64;
+
+        let area = twice_area as i64;
+
+        Gradient {
+            origin_x: v[0].x,
+            origin_y: v[0].y,
+            base: (a0 << 12) + (1 << 11),
+            dx: ((a1 * dy2 - a2 * dy1) << 12) / area,
+            dy: ((dx1 * a2 - dx2 * a1) << 12) / area,
+        }
+    }
+
+    pub fn at(&self, x: i32, y: i32) -> i32 {
+        (self.at_unclamped(x, y) >> 12).clamp(0, 255) as i32
+    }
+
+    /// Unclamped value at `(x, y)` with 12 fractional bits, the next
+    /// pixels on the row follow by adding `dx`.
+    pub fn at_unclamped(&self, x: i32, y: i32) -> i64 {
+        let x = (x - self.origin_x) as i64;
+        let y = (y - self.origin_y) as i64;
+
+        self.base + x * self.dx + y * self.dy
+    }
+
+    /// Change of the value from one pixel to the next on a row, with 12
+    /// fractional bits.
+    pub fn dx(&self) -> i64 {
+        self.dx
+    }
+
+    /// Same as `at` with 12 more bits of precision.
+    pub fn at_fixed(&self, x: i32, y: i32) -> i32 {
+        self.at_unclamped(x, y).clamp(0, (256 << 12) - 1) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vram::VRam;
+
+    use super::{draw_line, draw_quad, draw_rectangle, draw_triangle};
+    use super::{Color, DrawArea, DrawState, SemiTransparency, Vertex};
+    use super::{Texture, TextureDepth, TextureWindow};
+
+    /// Opaque state drawing anywhere in VRAM.
+    fn state() -> DrawState {
+        DrawState {
+            area: DrawArea {
+                left: 0,
+                top: 0,
+                right: 1023,
+                bottom: 511,
+            },
+            semi_transparency: None,
+            set_mask: false,
+            check_mask: false,
+            dither: false,
+            scale: 1,
+            skipped_lines: None,
+        }
+    }
+
+    fn vertex(x: i32, y: i32, color: Color) -> Vertex {
+        Vertex { x, y, color, u: 0, v: 0 }
+    }
+
+    /// 8bit colour that ends up as 1 in the red channel.
+    const RED_1: Color = Color { r: 8, g: 0, b: 0 };
+
+    #[test]
+    fn quad_covers_each_pixel_once() {
+        let mut vram = VRam::new();
+
+        // Additive so that pixels drawn by both triangles read 2.
+        let state = DrawState {
+            semi_transparency: Some(SemiTransparency::Add),
+            ..state()
+        };
+
+        let v = [
+            vertex(0, 0, RED_1),
+            vertex(16, 0, RED_1),
+            vertex(0, 16, RED_1),
+            vertex(16, 16, RED_1),
+        ];
+
+        let stats = draw_quad(&mut vram, &state, v, None);
+
+        assert_eq!(stats.pixels, 16 * 16);
+
+        // The right column and bottom row are left out.
+        for y in 0..17 {
+            for x in 0..17 {
+                let expected = match x < 16 && y < 16 {
+                    true => 1,
+                    false => 0,
+                };
+
+                assert_eq!(vram.load16(x, y), expected, "Pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn quad_split_is_0_1_2_and_1_2_3() {
+        let mut vram = VRam::new();
+
+        let state = DrawState {
+            semi_transparency: Some(SemiTransparency::Add),
+            ..state()
+        };
+
+        // Concave at vertex 3: triangle 1-2-3 lies within 0-1-2 so its
+        // pixels are drawn twice. Split along 0-3 they wouldn't be drawn
+        // at all.
+        let v = [
+            vertex(0, 0, RED_1),
+            vertex(32, 0, RED_1),
+            vertex(0, 32, RED_1),
+            vertex(8, 8, RED_1),
+        ];
+
+        draw_quad(&mut vram, &state, v, None);
+
+        assert_eq!(vram.load16(4, 4), 1);
+        assert_eq!(vram.load16(24, 2), 1);
+        assert_eq!(vram.load16(14, 14), 2);
+        assert_eq!(vram.load16(17, 17), 0);
+    }
+
+    #[test]
+    fn triangle_follows_top_left_rule() {
+        let mut vram = VRam::new();
+
+        draw_triangle(&mut vram, &state(), [vertex(0, 0, RED_1), vertex(8, 0, RED_1), vertex(0, 8, RED_1)], None);
+
+        // Top and left edges are drawn.
+        assert_eq!(vram.load16(0, 0), 1);
+        assert_eq!(vram.load16(7, 0), 1);
+        assert_eq!(vram.load16(0, 7), 1);
+        // Pixels on the bottom-right edge aren't.
+        assert_eq!(vram.load16(4, 4), 0);
+        assert_eq!(vram.load16(3, 4), 1);
+        assert_eq!(vram.load16(8, 0), 0);
+    }
+
+    #[test]
+    fn oversized_polygons_are_dropped() {
+        let mut vram = VRam::new();
+
+        let triangle = |vram: &mut VRam, (x, y): (i32, i32)| {
+            draw_triangle(vram, &state(), [vertex(0, 0, RED_1), vertex(x, 0, RED_1), vertex(0, y, RED_1)], None)
+        };
+
+        assert!(triangle(&mut vram, (1023, 10)).pixels > 0);
+        assert_eq!(triangle(&mut vram, (1024, 10)).pixels, 0);
+
+        assert!(triangle(&mut vram, (10, 511)).pixels > 0);
+        assert_eq!(triangle(&mut vram, (10, 512)).pixels, 0);
+    }
+
+    /// Raw texture with its page at (64, 0) and its CLUT at (0, 256).
+    fn texture(depth: TextureDepth) -> Texture {
+        Texture {
+            page_x: 64,
+            page_y: 0,
+            depth,
+            clut_x: 0,
+            clut_y: 256,
+            window: TextureWindow {
+                x_mask: 0,
+                y_mask: 0,
+                x_offset: 0,
+                y_offset: 0,
+            },
+            raw: true,
+            replacement: None,
+        }
+    }
+
+    /// CLUT whose entry `i` is `0x100 + i`.
+    fn clut(vram: &mut VRam) {
+        for i in 0..256 {
+            vram.store16(i, 256, 0x100 + i);
+        }
+    }
+
+    #[test]
+    fn clut_attribute_decoding() {
+        assert_eq!(Texture::clut_from_attribute(0x4028), (640, 256));
+        assert_eq!(Texture::clut_from_attribute(0xffff), (1008, 511));
+    }
+
+    #[test]
+    fn fetch_4bit_texels_through_clut() {
+        let mut vram = VRam::new();
+
+        clut(&mut vram);
+
+        vram.store16(64, 0, 0x3210);
+        vram.store16(65, 0, 0xfedc);
+        vram.store16(64, 1, 0x000f);
+
+        let t = texture(TextureDepth::T4Bit);
+
+        let texels: Vec<u16> = (0..8).map(|u| t.fetch(&vram, u, 0)).collect();
+
+        assert_eq!(texels, [0x100, 0x101, 0x102, 0x103, 0x10c, 0x10d, 0x10e, 0x10f]);
+        assert_eq!(t.fetch(&vram, 0, 1), 0x10f);
+    }
+
+    #[test]
+    fn fetch_8bit_texels_through_clut() {
+        let mut vram = VRam::new();
+
+        clut(&mut vram);
+
+        vram.store16(64, 0, 0x8001);
+        vram.store16(65, 0, 0x00ff);
+
+        let t = texture(TextureDepth::T8Bit);
+
+        let texels: Vec<u16> = (0..4).map(|u| t.fetch(&vram, u, 0)).collect();
+
+        assert_eq!(texels, [0x101, 0x180, 0x1ff, 0x100]);
+    }
+
+    #[test]
+    fn fetch_15bit_texels_directly() {
+        let mut vram = VRam::new();
+
+        clut(&mut vram);
+
+        vram.store16(67, 2, 0x7abc);
+
+        let t = texture(TextureDepth::T15Bit);
+
+        assert_eq!(t.fetch(&vram, 3, 2), 0x7abc);
+        assert_eq!(t.fetch(&vram, 0, 0), 0);
+    }
+
+    #[test]
+    fn texture_window_masks_coordinates() {
+        let mut vram = VRam::new();
+
+        vram.store16(64 + 11, 21, 0x1234);
+
+        let t = Texture {
+            // u bit 3 forced to 1, v bit 4 forced to 1.
+            window: TextureWindow {
+                x_mask: 1,
+                y_mask: 2,
+                x_offset: 1,
+                y_offset: 3,
+            },
+            ..texture(TextureDepth::T15Bit)
+        };
+
+        assert_eq!(t.fetch(&vram, 3, 5), 0x1234);
+        assert_eq!(t.fetch(&vram, 11, 21), 0x1234);
+        // Bits outside the mask go through.
+        assert_eq!(t.fetch(&vram, 2, 5), 0);
+    }
+
+    #[test]
+    fn texel_0_is_transparent() {
+        let mut vram = VRam::new();
+
+        for x in 0..3 {
+            vram.store16(x, 0, 0x7fff);
+        }
+
+        vram.store16(64, 0, 0x0000);
+        vram.store16(65, 0, 0x001f);
+        // Black with the STP bit set is drawn.
+        vram.store16(66, 0, 0x8000);
+
+        let t = Some(texture(TextureDepth::T15Bit));
+
+        let stats = draw_rectangle(&mut vram, &state(), vertex(0, 0, RED_1), 3, 1, t, (false, false));
+
+        assert_eq!([vram.load16(0, 0), vram.load16(1, 0), vram.load16(2, 0)], [0x7fff, 0x001f, 0x8000]);
+        assert_eq!((stats.pixels, stats.texels), (2, 3));
+    }
+
+    fn rgb(r: u16, g: u16, b: u16) -> u16 {
+        r | (g << 5) | (b << 10)
+    }
+
+    #[test]
+    fn blend_equations() {
+        let (back, front) = (rgb(20, 10, 4), rgb(8, 16, 31));
+
+        assert_eq!(SemiTransparency::Average.blend(back, front), rgb(14, 13, 17));
+        assert_eq!(SemiTransparency::Add.blend(back, front), rgb(28, 26, 31));
+        assert_eq!(SemiTransparency::Subtract.blend(back, front), rgb(12, 0, 0));
+        assert_eq!(SemiTransparency::AddQuarter.blend(back, front), rgb(22, 14, 11));
+
+        // The mask bit comes from the pixel being drawn.
+        assert_eq!(SemiTransparency::Add.blend(back | 0x8000, front), rgb(28, 26, 31));
+        assert_eq!(SemiTransparency::Add.blend(back, front | 0x8000), rgb(28, 26, 31) | 0x8000);
+    }
+
+    #[test]
+    fn textured_pixels_blend_with_stp_only() {
+        let mut vram = VRam::new();
+
+        vram.store16(0, 0, 0x0001);
+        vram.store16(1, 0, 0x0001);
+
+        vram.store16(64, 0, 0x0001);
+        vram.store16(65, 0, 0x8001);
+
+        let state = DrawState {
+            semi_transparency: Some(SemiTransparency::Add),
+            ..state()
+        };
+
+        let t = Some(texture(TextureDepth::T15Bit));
+
+        draw_rectangle(&mut vram, &state, vertex(0, 0, RED_1), 2, 1, t, (false, false));
+
+        assert_eq!(vram.load16(0, 0), 0x0001);
+        assert_eq!(vram.load16(1, 0), 0x8002);
+
+        // Untextured pixels always blend.
+        draw_rectangle(&mut vram, &state, vertex(0, 0, RED_1), 1, 1, None, (false, false));
+
+        assert_eq!(vram.load16(0, 0), 0x0002);
+    }
+
+    #[test]
+    fn mask_bit_set_and_check() {
+        let mut vram = VRam::new();
+
+        let set = DrawState {
+            set_mask: true,
+            ..state()
+        };
+
+        draw_rectangle(&mut vram, &set, vertex(0, 0, RED_1), 2, 1, None, (false, false));
+
+        assert_eq!(vram.load16(0, 0), 0x8001);
+
+        // Protect (0, 0) only.
+        vram.store16(1, 0, 0x0003);
+
+        let check = DrawState {
+            check_mask: true,
+            ..state()
+        };
+
+        let red = vertex(0, 0, Color { r: 0xff, g: 0, b: 0 });
+
+        let stats = draw_rectangle(&mut vram, &check, red, 2, 1, None, (false, false));
+
+        assert_eq!([vram.load16(0, 0), vram.load16(1, 0)], [0x8001, 0x001f]);
+        assert_eq!(stats.pixels, 1);
+    }
+
+    /// Pixels of the 32x32 block at the origin that aren't 0.
+    fn drawn(vram: &VRam) -> Vec<(u16, u16)> {
+        let mut pixels = Vec::new();
+
+        for y in 0..32 {
+            for x in 0..32 {
+                if vram.load16(x, y) != 0 {
+                    pixels.push((x, y));
+                }
+            }
+        }
+
+        pixels
+    }
+
+    #[test]
+    fn line_includes_both_endpoints() {
+        let mut vram = VRam::new();
+
+        let stats = draw_line(&mut vram, &state(), vertex(0, 0, RED_1), vertex(7, 3, RED_1));
+
+        let pixels = drawn(&vram);
+
+        assert_eq!(stats.pixels, 8);
+        assert_eq!(pixels.len(), 8);
+        assert!(pixels.contains(&(0, 0)) && pixels.contains(&(7, 3)));
+
+        // Same pixels drawn the other way around.
+        let mut reversed = VRam::new();
+
+        draw_line(&mut reversed, &state(), vertex(7, 3, RED_1), vertex(0, 0, RED_1));
+
+        assert_eq!(drawn(&reversed), pixels);
+
+        // Single pixel.
+        let mut dot = VRam::new();
+
+        draw_line(&mut dot, &state(), vertex(5, 5, RED_1), vertex(5, 5, RED_1));
+
+        assert_eq!(drawn(&dot), [(5, 5)]);
+    }
+
+    #[test]
+    fn line_shading_runs_from_end_to_end() {
+        let mut vram = VRam::new();
+
+        let black = Color { r: 0, g: 0, b: 0 };
+        let red = Color { r: 0xf8, g: 0, b: 0 };
+
+        draw_line(&mut vram, &state(), vertex(0, 1, black), vertex(0, 31, red));
+
+        let reds: Vec<u16> = (1..32).map(|y| vram.load16(0, y)).collect();
+
+        assert_eq!(reds[0], 0);
+        assert_eq!(reds[30], 31);
+        assert!(reds.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
@@ -0,0 +1,471 @@
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use rasterizer::{self, DrawArea, DrawState, DrawStats, Texture, Vertex};
+use texture_pack::TexturePack;
+use tiles::{self, TilePool};
+use vram::{Surface, VRam};
+
+/// Number of commands queued before they're handed over to the worker.
+const BATCH_SIZE: usize = 64;
+
+/// GP0 work that touches VRAM.
+#[derive(Clone)]
+pub enum Command {
+    Triangle(DrawState, [Vertex; 3], Option<Texture>),
+    Quad(DrawState, [Vertex; 4], Option<Texture>),
+    Rectangle {
+        state: DrawState,
+        vertex: Vertex,
+        size: (i32, i32),
+        texture: Option<Texture>,
+        flip: (bool, bool),
+    },
+    Line(DrawState, Vertex, Vertex),
+    /// GP0 0x02, ignores the mask settings but not the interlaced line
+    /// skipping, see `DrawState::skipped_lines`.
+    Fill {
+        position: (u16, u16),
+        size: (u16, u16),
+        color: u16,
+        skipped_lines: Option<i32>,
+    },
+    /// GP0 0x80.
+    Copy {
+        src: (u16, u16),
+        dst: (u16, u16),
+        size: (u16, u16),
+        mask: MaskSettings,
+    },
+    /// One row of a GP0 0xA0 upload, wrapping around the right edge of
+    /// VRAM.
+    Store {
+        position: (u16, u16),
+        pixels: Vec<u16>,
+        mask: MaskSettings,
+    },
+    /// GP0 0x01 or a texture page change, empties the texture cache.
+    ClearCache,
+}
+
+/// GP0 0xE6 settings for the commands that don't go through the
+/// rasterizer.
+#[derive(Clone, Copy)]
+pub struct MaskSettings {
+    pub set_mask: bool,
+    pub check_mask: bool,
+}
+
+impl Command {
+    pub fn execute(self, vram: &mut VRam) -> DrawStats {
+        let command = match vram.has_texture_pack() {
+            true => self.replaced(vram),
+            false => self,
+        };
+
+        match command {
+            Command::Triangle(state, v, texture) => rasterizer::draw_triangle(vram, &state, v, texture),
+            Command::Quad(state, v, texture) => rasterizer::draw_quad(vram, &state, v, texture),
+            Command::Rectangle { state, vertex, size: (width, height), texture, flip } => {
+                rasterizer::draw_rectangle(vram, &state, vertex, width, height, texture, flip)
+            },
+            Command::Line(state, a, b) => rasterizer::draw_line(vram, &state, a, b),
+            Command::Fill { position: (x, y), size: (width, height), color, skipped_lines } => {
+                for dy in 0..height {
+                    let y = y.wrapping_add(dy);
+
+                    if skipped_lines == Some((y & 1) as i32) {
+                        continue;
+                    }
+
+                    for dx in 0..width {
+                        let x = x.wrapping_add(dx);
+
+                        vram.store16(x, y, color);
+                        vram.replicate(x, y);
+                    }
+                }
+
+                DrawStats::default()
+            },
+            Command::Copy { src, dst, size: (width, height), mask } => {
+                // The GPU copies rows left to right, except when the
+                // destination is to the right of the source where it goes
+                // backwards so that overlapping copies don't smear.
+                let reverse = src.0 < dst.0;
+
+                for dy in 0..height {
+                    let sy = src.1.wrapping_add(dy);
+                    let ty = dst.1.wrapping_add(dy);
+
+                    for i in 0..width {
+                        let dx = match reverse {
+                            true => width - 1 - i,
+                            false => i,
+                        };
+
+                        let (sx, tx) = (src.0.wrapping_add(dx), dst.0.wrapping_add(dx));
+
+                        let pixel = vram.load16(sx, sy);
+
+                        if store_masked(vram, mask, tx, ty, pixel) {
+                            let set_mask = (mask.set_mask as u16) << 15;
+
+                            vram.copy_upscaled((sx, sy), (tx, ty), set_mask);
+                        }
+                    }
+                }
+
+                DrawStats::default()
+            },
+            Command::Store { position: (x, y), pixels, mask } => {
+                for (dx, &pixel) in pixels.iter().enumerate() {
+                    let x = x.wrapping_add(dx as u16) & 0x3ff;
+
+                    if store_masked(vram, mask, x, y, pixel) {
+                        vram.replicate(x, y);
+                    }
+                }
+
+                DrawStats::default()
+            },
+            Command::ClearCache => {
+                vram.invalidate_texture_cache();
+
+                DrawStats::default()
+            },
+        }
+    }
+
+    /// Same command with the texture replacement for the page it samples,
+    /// if the texture pack has one.
+    fn replaced(self, vram: &mut VRam) -> Command {
+        let mut command = self;
+
+        let texture = match command {
+            Command::Triangle(_, _, Some(ref mut texture)) => texture,
+            Command::Quad(_, _, Some(ref mut texture)) => texture,
+            Command::Rectangle { texture: Some(ref mut texture), .. } => texture,
+            _ => return command,
+        };
+
+        texture.replacement = vram.replace_texture(texture);
+
+        command
+    }
+
+    /// Drawing environment of the commands going through the rasterizer.
+    pub fn draw_state(&self) -> Option<DrawState> {
+        match *self {
+            Command::Triangle(state, ..) => Some(state),
+            Command::Quad(state, ..) => Some(state),
+            Command::Rectangle { state, .. } => Some(state),
+            Command::Line(state, ..) => Some(state),
+            _ => None,
+        }
+    }
+
+    /// Same command restricted to `area`, `None` if nothing of it is left.
+    pub fn clipped(&self, area: &DrawArea) -> Option<Command> {
+        let mut command = self.clone();
+
+        let state = match command {
+            Command::Triangle(ref mut state, ..) => state,
+            Command::Quad(ref mut state, ..) => state,
+            Command::Rectangle { ref mut state, .. } => state,
+            Command::Line(ref mut state, ..) => state,
+            _ => return Some(command),
+        };
+
+        state.area = state.area.intersection(area)?;
+
+        Some(command)
+    }
+
+    /// VRAM areas the texture and CLUT are read from.
+    pub fn texture_reads(&self) -> Vec<DrawArea> {
+        let texture = match *self {
+            Command::Triangle(_, _, texture) => texture,
+            Command::Quad(_, _, texture) => texture,
+            Command::Rectangle { texture, .. } => texture,
+            _ => None,
+        };
+
+        match texture {
+            Some(t) => t.vram_areas(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Draws the command into a tile, using the span rasterizer for
+    /// polygons.
+    pub fn execute_tile<S: Surface>(self, tile: &mut S) -> DrawStats {
+        match self {
+            Command::Triangle(state, v, texture) => tiles::draw_triangle(tile, &state, v, texture),
+            Command::Quad(state, v, texture) => {
+                let mut stats = tiles::draw_triangle(tile, &state, [v[0], v[1], v[2]], texture);
+
+                stats += tiles::draw_triangle(tile, &state, [v[1], v[2], v[3]], texture);
+
+                stats
+            },
+            Command::Rectangle { state, vertex, size: (width, height), texture, flip } => {
+                rasterizer::draw_rectangle(tile, &state, vertex, width, height, texture, flip)
+            },
+            Command::Line(state, a, b) => rasterizer::draw_line(tile, &state, a, b),
+            _ => panic!("Command can't be drawn into a tile"),
+        }
+    }
+
+    /// Work the command is expected to take, computed from the geometry
+    /// alone so that it doesn't depend on VRAM contents.
+    fn estimate(&self) -> DrawStats {
+        match *self {
+            Command::Triangle(state, v, texture) => {
+                rasterizer::estimate_triangle(&state, v, texture.is_some())
+            },
+            Command::Quad(state, v, texture) => {
+                let mut stats = rasterizer::estimate_triangle(&state, [v[0], v[1], v[2]], texture.is_some());
+
+                stats += rasterizer::estimate_triangle(&state, [v[1], v[2], v[3]], texture.is_some());
+
+                stats
+            },
+            Command::Rectangle { state, vertex, size, texture, .. } => {
+                rasterizer::estimate_rectangle(&state, vertex, size, texture.is_some())
+            },
+            Command::Line(_, a, b) => rasterizer::estimate_line(a, b),
+            // The caller accounts for these itself.
+            _ => DrawStats::default(),
+        }
+    }
+}
+
+/// Writes a pixel honouring the mask settings, returns false if the pixel
+/// was protected.
+fn store_masked(vram: &mut VRam, mask: MaskSettings, x: u16, y: u16, pixel: u16) -> bool {
+    if mask.check_mask && vram.load16(x, y) & 0x8000 != 0 {
+        return false;
+    }
+
+    vram.store16(x, y, pixel | ((mask.set_mask as u16) << 15));
+
+    true
+}
+
+enum Message {
+    Commands(Vec<Command>),
+    /// Answered once every command received before it has been executed,
+    /// with the work they did since the previous sync.
+    Sync(Sender<DrawStats>),
+    Quit,
+}
+
+/// Owner of VRAM, executes the drawing commands either right away or on a
+/// worker thread.
+pub struct Renderer {
+    vram: Arc<Mutex<VRam>>,
+    worker: Option<Worker>,
+    /// Threads the worker splits VRAM between, 1 to draw everything on the
+    /// worker itself.
+    tile_threads: usize,
+    /// Work done by the commands executed since the last `take_stats`.
+    stats: DrawStats,
+}
+
+struct Worker {
+    sender: Sender<Message>,
+    thread: Option<JoinHandle<()>>,
+    /// Commands not sent yet.
+    batch: Vec<Command>,
+    /// Commands were sent since the last sync.
+    pending: bool,
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer {
+            vram: Arc::new(Mutex::new(VRam::new())),
+            worker: None,
+            tile_threads: 1,
+            stats: DrawStats::default(),
+        }
+    }
+
+    /// In threaded mode the commands run on a worker thread, the CPU thread
+    /// only waits for it when reading VRAM back.
+    pub fn set_threaded(&mut self, threaded: bool) {
+        match threaded {
+            true => {
+                if self.worker.is_none() {
+                    self.worker = Some(Worker::spawn(self.vram.clone(), self.tile_threads));
+                }
+            },
+            false => {
+                self.sync();
+                self.worker = None;
+            },
+        }
+    }
+
+    /// Samples textures through the emulated texture cache.
+    pub fn set_texture_cache(&mut self, enabled: bool) {
+        self.sync();
+        self.vram().set_texture_cache(enabled);
+    }
+
+    /// Dumps textures to and/or replaces them with the images of a texture
+    /// pack, `None` to stop.
+    pub fn set_texture_pack(&mut self, pack: Option<TexturePack>) {
+        self.sync();
+        self.vram().set_texture_pack(pack);
+    }
+
+    /// Has the worker draw with the tile-parallel rasterizer using
+    /// `threads` threads.
+    pub fn set_tile_threads(&mut self, threads: usize) {
+        self.tile_threads = threads.max(1);
+
+        if self.worker.is_some() {
+            self.set_threaded(false);
+            self.set_threaded(true);
+        }
+    }
+
+    /// Runs or queues `command`. Returns the work it's expected to take,
+    /// computed the same way whether it runs right away or on the worker so
+    /// that the GPU timing doesn't depend on the rendering mode. The work
+    /// actually done is counted by `take_stats`.
+    pub fn draw(&mut self, command: Command) -> DrawStats {
+        let estimate = command.estimate();
+
+        match self.worker {
+            Some(ref mut worker) => {
+                worker.batch.push(command);
+
+                if worker.batch.len() >= BATCH_SIZE {
+                    worker.flush();
+                }
+            },
+            None => {
+                self.stats += command.execute(&mut self.vram.lock().unwrap());
+            },
+        }
+
+        estimate
+    }
+
+    /// Waits until every queued command has been executed.
+    pub fn sync(&mut self) {
+        if let Some(ref mut worker) = self.worker {
+            self.stats += worker.sync();
+        }
+    }
+
+    /// Pixels written and texels fetched since the last call, waits for the
+    /// queued commands.
+    pub fn take_stats(&mut self) -> DrawStats {
+        self.sync();
+
+        mem::take(&mut self.stats)
+    }
+
+    /// Access to VRAM, `sync` must be called first for the result of the
+    /// queued commands to be visible.
+    pub fn vram(&self) -> MutexGuard<'_, VRam> {
+        self.vram.lock().unwrap()
+    }
+}
+
+impl Worker {
+    fn spawn(vram: Arc<Mutex<VRam>>, tile_threads: usize) -> Worker {
+        let (sender, receiver) = channel();
+
+        let thread = thread::Builder::new()
+            .name("gpu".to_string())
+            .spawn(move || Worker::run(vram, receiver, tile_threads))
+            .unwrap();
+
+        Worker {
+            sender,
+            thread: Some(thread),
+            batch: Vec::with_capacity(BATCH_SIZE),
+            pending: false,
+        }
+    }
+
+    fn run(vram: Arc<Mutex<VRam>>, receiver: Receiver<Message>, tile_threads: usize) {
+        let mut stats = DrawStats::default();
+
+        let pool = match tile_threads {
+            1 => None,
+            _ => Some(TilePool::new(tile_threads)),
+        };
+
+        for message in receiver.iter() {
+            match message {
+                Message::Commands(commands) => {
+                    let mut vram = vram.lock().unwrap();
+
+                    match pool {
+                        Some(ref pool) => stats += tiles::execute(&mut vram, commands, pool),
+                        None => {
+                            for command in commands {
+                                stats += command.execute(&mut vram);
+                            }
+                        },
+                    }
+                },
+                Message::Sync(reply) => {
+                    let _ = reply.send(mem::take(&mut stats));
+                },
+                Message::Quit => break,
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+
+        let batch = mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+
+        self.sender.send(Message::Commands(batch)).unwrap();
+
+        self.pending = true;
+    }
+
+    /// Returns the work done since the previous sync.
+    fn sync(&mut self) -> DrawStats {
+        self.flush();
+
+        if !self.pending {
+            return DrawStats::default();
+        }
+
+        let (reply, done) = channel();
+
+        self.sender.send(Message::Sync(reply)).unwrap();
+
+        let stats = done.recv().unwrap();
+
+        self.pending = false;
+
+        stats
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.flush();
+
+        let _ = self.sender.send(Message::Quit);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use rasterizer::{Texture, TextureDepth};
+
+/// Number of 8 byte lines in the 2KB texture cache.
+const LINES: usize = 256;
+
+/// The GPU's texture cache. Texels are read from VRAM 4 halfwords at a time
+/// and kept until GP0 0x01 or a texture page change, so VRAM writes made in
+/// between aren't seen by primitives sampling cached texels.
+pub struct TextureCache {
+    lines: Vec<CacheLine>,
+    clut: ClutCache,
+}
+
+#[derive(Clone, Copy)]
+struct CacheLine {
+    /// VRAM position of the first halfword, `None` when invalid.
+    tag: Option<(u16, u16)>,
+    data: [u16; 4],
+}
+
+/// Palette of the last CLUT used, only reloaded when a primitive uses
+/// another one.
+struct ClutCache {
+    /// Position and depth of the cached palette, `None` when invalid.
+    tag: Option<(u16, u16, TextureDepth)>,
+    entries: Vec<u16>,
+}
+
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache {
+            lines: vec![CacheLine { tag: None, data: [0; 4] }; LINES],
+            clut: ClutCache {
+                tag: None,
+                entries: vec![0; 256],
+            },
+        }
+    }
+
+    /// Drops every cached texel and the palette.
+    pub fn invalidate(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.tag = None;
+        }
+
+        self.clut.tag = None;
+    }
+
+    /// Same as `Texture::fetch` but going through the cache, `load` reads a
+    /// halfword from VRAM.
+    pub fn fetch<F>(&mut self, texture: &Texture, u: u8, v: u8, load: F) -> u16
+        where F: Fn(u16, u16) -> u16 {
+        let (x, y, u) = texture.address(u, v);
+
+        let word = self.load_texel(texture.depth, x, y, &load);
+
+        match texture.palette_index(word, u) {
+            Some(index) => self.load_clut(texture, &load)[index as usize],
+            None => word,
+        }
+    }
+
+    fn load_texel<F>(&mut self, depth: TextureDepth, x: u16, y: u16, load: &F) -> u16
+        where F: Fn(u16, u16) -> u16 {
+        // The cache covers 64x64 texels in 4bit mode, 32x32 halfwords
+        // otherwise.
+        let index = match depth {
+            TextureDepth::T4Bit => ((y & 63) << 2) | ((x >> 2) & 3),
+            _ => ((y & 31) << 3) | ((x >> 2) & 7),
+        };
+
+        let line = &mut self.lines[index as usize];
+
+        let base = x & !3;
+
+        if line.tag != Some((base, y)) {
+            for (i, halfword) in line.data.iter_mut().enumerate() {
+                *halfword = load(base + i as u16, y);
+            }
+
+            line.tag = Some((base, y));
+        }
+
+        line.data[(x & 3) as usize]
+    }
+
+    fn load_clut<F>(&mut self, texture: &Texture, load: &F) -> &[u16]
+        where F: Fn(u16, u16) -> u16 {
+        let tag = Some((texture.clut_x, texture.clut_y, texture.depth));
+
+        if self.clut.tag != tag {
+            let count = match texture.depth {
+                TextureDepth::T4Bit => 16,
+                _ => 256,
+            };
+
+            for i in 0..count {
+                self.clut.entries[i] = load(texture.clut_x + i as u16, texture.clut_y);
+            }
+
+            self.clut.tag = tag;
+        }
+
+        &self.clut.entries
+    }
+}
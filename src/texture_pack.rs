@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gpu::rgb_from_15bit;
+use image;
+use rasterizer::{Texture, TextureDepth, TextureWindow};
+use vram::VRam;
+
+/// Number of pages remembered before starting over, games streaming
+/// textures would otherwise grow the map forever.
+const MAX_PAGES: usize = 0x10000;
+
+/// Width and height of a texture page in texels, whatever the depth.
+const PAGE_SIZE: u32 = 256;
+
+/// Replacement image a primitive samples instead of VRAM.
+#[derive(Clone, Copy)]
+pub struct Replacement {
+    image: usize,
+    /// Texture window of the primitive, applied to its texture coordinates
+    /// before they're mapped into the image.
+    window: TextureWindow,
+}
+
+/// Dumps and replaces textures, identified by the hash of the decoded
+/// texels of the whole texture page a primitive samples so that every
+/// primitive drawing from the same page and CLUT shares one image. Dumps
+/// and replacements both live in `dir` as `<hash>.png`.
+pub struct TexturePack {
+    dir: PathBuf,
+    dump: bool,
+    replace: bool,
+    /// Textures met so far and their replacement image if any.
+    known: HashMap<u64, Option<usize>>,
+    /// Result of the last lookup of each page along with the VRAM write
+    /// counter at the time, reused until the page or CLUT are written.
+    pages: HashMap<PageKey, (u64, Option<usize>)>,
+    images: Vec<ReplacementImage>,
+}
+
+/// Everything the texels of a page depend on besides VRAM contents.
+#[derive(PartialEq, Eq, Hash)]
+struct PageKey {
+    page: (u16, u16),
+    depth: TextureDepth,
+    clut: (u16, u16),
+}
+
+impl PageKey {
+    fn new(texture: &Texture) -> PageKey {
+        let clut = match texture.depth {
+            TextureDepth::T15Bit => (0, 0),
+            _ => (texture.clut_x, texture.clut_y),
+        };
+
+        PageKey {
+            page: (texture.page_x, texture.page_y),
+            depth: texture.depth,
+            clut,
+        }
+    }
+}
+
+/// Replacement texels as 15bit colours with bit 15 set, 0 where the image
+/// is fully transparent.
+struct ReplacementImage {
+    width: u32,
+    height: u32,
+    texels: Vec<u16>,
+}
+
+impl TexturePack {
+    pub fn new(dir: PathBuf, dump: bool, replace: bool) -> TexturePack {
+        if dump {
+            if let Err(e) = fs::create_dir_all(&dir) {
+                println!("Can't create texture directory {}: {}", dir.display(), e);
+            }
+        }
+
+        TexturePack {
+            dir,
+            dump,
+            replace,
+            known: HashMap::new(),
+            pages: HashMap::new(),
+            images: Vec::new(),
+        }
+    }
+
+    /// Hashes the page and CLUT of `texture`, dumping it the first time
+    /// it's seen. Returns its replacement if there's one.
+    pub fn lookup(&mut self, vram: &VRam, texture: &Texture) -> Option<Replacement> {
+        let key = PageKey::new(texture);
+
+        let replacement = |image: Option<usize>| {
+            image.map(|image| Replacement { image, window: texture.window })
+        };
+
+        if let Some(&(time, image)) = self.pages.get(&key) {
+            let written = texture.vram_areas().iter().any(|area| vram.written_since(area, time));
+
+            if !written {
+                return replacement(image);
+            }
+        }
+
+        let image = self.lookup_texels(vram, texture);
+
+        if self.pages.len() >= MAX_PAGES {
+            self.pages.clear();
+        }
+
+        self.pages.insert(key, (vram.write_time(), image));
+
+        replacement(image)
+    }
+
+    /// Hashes the texels of the page, dumping and loading as needed.
+    fn lookup_texels(&mut self, vram: &VRam, texture: &Texture) -> Option<usize> {
+        let texels = page_texels(vram, texture);
+
+        let hash = hash_texels(&texels);
+
+        if let Some(&image) = self.known.get(&hash) {
+            return image;
+        }
+
+        let path = self.dir.join(format!("{:016x}.png", hash));
+
+        if self.dump && !path.exists() {
+            let rgba = texels_to_rgba(&texels);
+
+            if let Err(e) = image::save(&path, PAGE_SIZE, PAGE_SIZE, &rgba) {
+                println!("Can't dump texture {}: {}", path.display(), e);
+            }
+        }
+
+        let image = match self.replace && path.exists() {
+            true => self.load(&path),
+            false => None,
+        };
+
+        self.known.insert(hash, image);
+
+        image
+    }
+
+    fn load(&mut self, path: &Path) -> Option<usize> {
+        let (width, height, rgba) = match image::load(path) {
+            Ok(image) => image,
+            Err(e) => {
+                println!("Can't load replacement texture {}: {}", path.display(), e);
+                return None;
+            },
+        };
+
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let texels = rgba.chunks(4).map(|p| {
+            match p[3] {
+                0 => 0,
+                _ => {
+                    let c = |v: u8| (v >> 3) as u16;
+
+                    0x8000 | c(p[0]) | (c(p[1]) << 5) | (c(p[2]) << 10)
+                },
+            }
+        }).collect();
+
+        self.images.push(ReplacementImage {
+            width,
+            height,
+            texels,
+        });
+
+        Some(self.images.len() - 1)
+    }
+
+    /// Replacement texel for a pixel whose texture coordinates are
+    /// `(u, v)` in 8.12 fixed point. The STP bit comes from the `original`
+    /// texel and, like on the hardware, black without it is transparent.
+    pub fn fetch(&self, replacement: &Replacement, original: u16, (u, v): (i32, i32)) -> u16 {
+        let image = &self.images[replacement.image];
+
+        let (wu, wv) = replacement.window.apply((u >> 12) as u8, (v >> 12) as u8);
+
+        // The page is stretched over the whole image.
+        let position = |texel: u8, coord: i32, image_size: u32| {
+            let fixed = ((texel as i64) << 12) | (coord & 0xfff) as i64;
+
+            ((fixed * image_size as i64 / PAGE_SIZE as i64) >> 12).min(image_size as i64 - 1) as u32
+        };
+
+        let x = position(wu, u, image.width);
+        let y = position(wv, v, image.height);
+
+        match image.texels[(y * image.width + x) as usize] {
+            0 => 0,
+            texel => (texel & 0x7fff) | (original & 0x8000),
+        }
+    }
+}
+
+/// Texels of the whole page as the rasterizer would fetch them without a
+/// texture window, row after row.
+fn page_texels(vram: &VRam, texture: &Texture) -> Vec<u16> {
+    let page = Texture {
+        window: TextureWindow {
+            x_mask: 0,
+            y_mask: 0,
+            x_offset: 0,
+            y_offset: 0,
+        },
+        ..*texture
+    };
+
+    let mut texels = Vec::with_capacity((PAGE_SIZE * PAGE_SIZE) as usize);
+
+    for v in 0..PAGE_SIZE {
+        for u in 0..PAGE_SIZE {
+            texels.push(page.fetch(vram, u as u8, v as u8));
+        }
+    }
+
+    texels
+}
+
+/// 64bit FNV-1a, stable across runs and builds so that the file names stay
+/// valid.
+fn hash_texels(texels: &[u16]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+
+    for &texel in texels {
+        for byte in texel.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    hash
+}
+
+fn texels_to_rgba(texels: &[u16]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(texels.len() * 4);
+
+    for &texel in texels {
+        let [r, g, b] = rgb_from_15bit(texel);
+
+        // Texel 0 is transparent when drawing.
+        let alpha = match texel {
+            0 => 0,
+            _ => 0xff,
+        };
+
+        rgba.extend_from_slice(&[r, g, b, alpha]);
+    }
+
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use image;
+    use rasterizer::{Color, DrawArea, DrawState, Texture, TextureDepth, TextureWindow, Vertex};
+    use renderer::Command;
+    use vram::VRam;
+
+    use super::TexturePack;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("texture_pack_{}_{}", name, process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+
+        dir
+    }
+
+    fn dumped(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect()
+    }
+
+    fn texture(depth: TextureDepth) -> Texture {
+        Texture {
+            page_x: 64,
+            page_y: 256,
+            depth,
+            clut_x: 0,
+            clut_y: 0,
+            window: TextureWindow {
+                x_mask: 0,
+                y_mask: 0,
+                x_offset: 0,
+                y_offset: 0,
+            },
+            raw: true,
+            replacement: None,
+        }
+    }
+
+    /// Raw 15bit textured rectangle at `(x, y)` sampling from `(u, v)`.
+    fn rectangle((x, y): (i32, i32), (u, v): (u8, u8), size: (i32, i32)) -> Command {
+        Command::Rectangle {
+            state: DrawState {
+                area: DrawArea {
+                    left: 0,
+                    top: 0,
+                    right: 1023,
+                    bottom: 511,
+                },
+                semi_transparency: None,
+                set_mask: false,
+                check_mask: false,
+                dither: false,
+                scale: 1,
+                skipped_lines: None,
+            },
+            vertex: Vertex { x, y, color: Color { r: 0x80, g: 0x80, b: 0x80 }, u, v },
+            size,
+            texture: Some(texture(TextureDepth::T15Bit)),
+            flip: (false, false),
+        }
+    }
+
+    #[test]
+    fn lookup_rehashes_after_vram_writes() {
+        let dir = test_dir("rehash");
+
+        let mut vram = VRam::new();
+
+        vram.set_texture_pack(Some(TexturePack::new(dir.clone(), true, false)));
+
+        let texture = texture(TextureDepth::T4Bit);
+
+        vram.store16(1, 0, 0x7fff);
+        vram.store16(64, 256, 0x0010);
+
+        vram.replace_texture(&texture);
+        assert_eq!(dumped(&dir).len(), 1);
+
+        // Writes to VRAM the texture doesn't use keep the cached result.
+        vram.store16(512, 0, 0x1234);
+        vram.replace_texture(&texture);
+        assert_eq!(dumped(&dir).len(), 1);
+
+        // Changing the CLUT changes the texels.
+        vram.store16(1, 0, 0x001f);
+        vram.replace_texture(&texture);
+        assert_eq!(dumped(&dir).len(), 2);
+
+        // And so does changing the page.
+        vram.store16(65, 257, 0x0001);
+        vram.replace_texture(&texture);
+        assert_eq!(dumped(&dir).len(), 3);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn primitives_sampling_one_page_share_a_dump() {
+        let dir = test_dir("share");
+
+        let mut vram = VRam::new();
+
+        vram.store16(64, 256, 0x7c00);
+        vram.set_texture_pack(Some(TexturePack::new(dir.clone(), true, false)));
+
+        rectangle((0, 0), (0, 0), (8, 8)).execute(&mut vram);
+        rectangle((0, 8), (128, 64), (16, 4)).execute(&mut vram);
+
+        assert_eq!(dumped(&dir).len(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replacement_is_stretched_over_the_page() {
+        let dir = test_dir("replace");
+
+        let mut vram = VRam::new();
+
+        vram.store16(64, 256, 0x7c00);
+        vram.set_texture_pack(Some(TexturePack::new(dir.clone(), true, false)));
+
+        rectangle((0, 0), (0, 0), (1, 1)).execute(&mut vram);
+
+        // Twice the page resolution, red on the left half, green on the
+        // right one.
+        let rgba: Vec<u8> = (0..512 * 512).flat_map(|i| match i % 512 < 256 {
+            true => [0xff, 0, 0, 0xff],
+            false => [0, 0xff, 0, 0xff],
+        }).collect();
+
+        image::save(&dumped(&dir)[0], 512, 512, &rgba).unwrap();
+
+        vram.set_texture_pack(Some(TexturePack::new(dir.clone(), false, true)));
+
+        rectangle((0, 0), (0, 0), (1, 1)).execute(&mut vram);
+        rectangle((1, 0), (200, 100), (1, 1)).execute(&mut vram);
+
+        assert_eq!((vram.load16(0, 0), vram.load16(1, 0)), (0x001f, 0x03e0));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,538 @@
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use rasterizer::{DrawArea, DrawState, DrawStats, Texture, TriangleSetup, Vertex, DITHER_MATRIX};
+use renderer::Command;
+use vram::{Surface, Tile, VRam};
+
+/// Pixels handled by one step of the span functions. The edge functions
+/// and attributes are computed once for the first pixel of the span and
+/// offset for the others instead of being evaluated at every pixel.
+const LANES: usize = 8;
+
+type Lanes<T> = [T; LANES];
+
+/// Threads drawing the bands of VRAM, started along with the renderer's
+/// worker and kept until it quits.
+pub struct TilePool {
+    bands: Vec<Sender<Band>>,
+    /// Stats of each band drawn, `None` if its thread panicked.
+    done: Receiver<Option<DrawStats>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Commands of a segment to draw into a tile.
+struct Band {
+    tile: Tile,
+    commands: Arc<Vec<Command>>,
+}
+
+impl TilePool {
+    pub fn new(threads: usize) -> TilePool {
+        let (finished, done) = channel();
+
+        let mut bands = Vec::new();
+        let mut handles = Vec::new();
+
+        for i in 0..threads {
+            let (sender, receiver) = channel::<Band>();
+            let finished = finished.clone();
+
+            let thread = thread::Builder::new()
+                .name(format!("gpu tile {}", i))
+                .spawn(move || {
+                    for band in receiver.iter() {
+                        // The band is gone once this returns, so that the
+                        // tile doesn't outlive the segment.
+                        let stats = panic::catch_unwind(AssertUnwindSafe(|| band.draw()));
+
+                        if finished.send(stats.ok()).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+
+            bands.push(sender);
+            handles.push(thread);
+        }
+
+        TilePool {
+            bands,
+            done,
+            threads: handles,
+        }
+    }
+}
+
+impl Drop for TilePool {
+    fn drop(&mut self) {
+        self.bands.clear();
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Band {
+    fn draw(self) -> DrawStats {
+        let Band { mut tile, commands } = self;
+
+        let band = DrawArea {
+            left: 0,
+            top: tile.top as i32,
+            right: 1023,
+            bottom: tile.bottom as i32,
+        };
+
+        let mut stats = DrawStats::default();
+
+        for command in commands.iter() {
+            if let Some(command) = command.clipped(&band) {
+                stats += command.execute_tile(&mut tile);
+            }
+        }
+
+        stats
+    }
+}
+
+/// Runs `commands` with the rows they draw to split into bands drawn in
+/// parallel by `pool`. The result and the returned stats are the same as
+/// running them one after the other.
+pub fn execute(vram: &mut VRam, commands: Vec<Command>, pool: &TilePool) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    // The texture cache contents depend on the order texels are fetched in
+    // and the texture pack isn't shared between threads.
+    if vram.has_texture_cache() || vram.has_texture_pack() {
+        for command in commands {
+            stats += command.execute(vram);
+        }
+
+        return stats;
+    }
+
+    let mut segment = Vec::new();
+    // Area written by the commands of the current segment.
+    let mut written: Option<DrawArea> = None;
+    // Texture and CLUT areas sampled by the commands of the segment.
+    let mut read: Vec<DrawArea> = Vec::new();
+
+    for command in commands {
+        let state = match command.draw_state() {
+            Some(state) => state,
+            None => {
+                stats += run_segment(vram, &mut segment, written.take(), pool);
+                read.clear();
+
+                stats += command.execute(vram);
+                continue;
+            },
+        };
+
+        let reads = command.texture_reads();
+
+        let overlaps = |areas: &[DrawArea], area: &DrawArea| {
+            areas.iter().any(|r| r.intersection(area).is_some())
+        };
+
+        // A primitive textured from its own drawing area must run alone.
+        // Otherwise tiles may run ahead of each other so a texture drawn by
+        // an earlier command of the segment might not be finished when
+        // another tile samples it, and one sampled by an earlier command
+        // might already be overwritten.
+        let feedback = overlaps(&reads, &state.area);
+
+        let hazard = written.is_some_and(|w| overlaps(&reads, &w)) || overlaps(&read, &state.area);
+
+        if feedback || hazard {
+            stats += run_segment(vram, &mut segment, written.take(), pool);
+            read.clear();
+        }
+
+        if feedback {
+            stats += command.execute(vram);
+            continue;
+        }
+
+        written = Some(match written {
+            Some(w) => w.union(&state.area),
+            None => state.area,
+        });
+
+        read.extend(reads);
+
+        segment.push(command);
+    }
+
+    stats += run_segment(vram, &mut segment, written, pool);
+
+    stats
+}
+
+/// Draws `segment`, whose commands write within `written`, with the rows
+/// of that area split between the pool threads.
+fn run_segment(vram: &mut VRam,
+               segment: &mut Vec<Command>,
+               written: Option<DrawArea>,
+               pool: &TilePool) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    let commands = Arc::new(mem::take(segment));
+
+    let area = match written {
+        Some(area) if area.top <= area.bottom => area,
+        // Nothing gets drawn.
+        _ => return stats,
+    };
+
+    // The segment never samples a texture from an area it draws to and
+    // blending only reads the pixel being written, so tiles only ever read
+    // their own rows or rows nobody writes.
+    let tiles = unsafe { vram.tiles(area.top as u16, area.bottom as u16, pool.bands.len()) };
+
+    let count = tiles.len();
+
+    for (tile, band) in tiles.into_iter().zip(pool.bands.iter()) {
+        band.send(Band { tile, commands: commands.clone() }).unwrap();
+    }
+
+    // VRAM must stay borrowed until every band is done with it, even if one
+    // of them failed.
+    let mut failed = false;
+
+    for _ in 0..count {
+        match pool.done.recv().unwrap() {
+            Some(band) => stats += band,
+            None => failed = true,
+        }
+    }
+
+    if failed {
+        panic!("Tile thread panicked");
+    }
+
+    stats
+}
+
+/// Same as `rasterizer::draw_triangle` but walking the triangle a span of
+/// pixels at a time.
+pub fn draw_triangle<S: Surface>(vram: &mut S,
+                                 state: &DrawState,
+                                 v: [Vertex; 3],
+                                 texture: Option<Texture>) -> DrawStats {
+    let stats = rasterize_triangle(vram, state, v, texture);
+
+    let scale = vram.scale() as i32;
+
+    if scale > 1 {
+        let v = [v[0].upscaled(scale), v[1].upscaled(scale), v[2].upscaled(scale)];
+
+        rasterize_triangle(vram, &state.upscaled(scale), v, texture);
+    }
+
+    stats
+}
+
+fn rasterize_triangle<S: Surface>(vram: &mut S,
+                                  state: &DrawState,
+                                  v: [Vertex; 3],
+                                  texture: Option<Texture>) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    let t = match TriangleSetup::new(state, v) {
+        Some(t) => t,
+        None => return stats,
+    };
+
+    let step = Interpolants::step(&t);
+
+    for y in t.y_start..=t.y_end {
+        if state.skips_line(y) {
+            continue;
+        }
+
+        let mut start = Interpolants::at(&t, t.x_start, y);
+
+        let mut x = t.x_start;
+
+        while x <= t.x_end {
+            let count = ((t.x_end - x + 1) as usize).min(LANES);
+
+            stats += draw_span(vram, state, &t, texture, (x, y), count, &start);
+
+            start.advance(&step, LANES as i32);
+
+            x += LANES as i32;
+        }
+    }
+
+    stats
+}
+
+/// Edge functions and attributes of a triangle at a pixel, or their change
+/// from one pixel to the next on a row.
+#[derive(Clone, Copy)]
+struct Interpolants {
+    edges: [i32; 3],
+    /// R, G, B, U and V with 12 fractional bits.
+    attributes: [i64; 5],
+}
+
+impl Interpolants {
+    fn at(t: &TriangleSetup, x: i32, y: i32) -> Interpolants {
+        Interpolants {
+            edges: t.edges(x, y),
+            attributes: [
+                t.r.at_unclamped(x, y),
+                t.g.at_unclamped(x, y),
+                t.b.at_unclamped(x, y),
+                t.u.at_unclamped(x, y),
+                t.v.at_unclamped(x, y),
+            ],
+        }
+    }
+
+    fn step(t: &TriangleSetup) -> Interpolants {
+        Interpolants {
+            edges: t.edges_dx(),
+            attributes: [t.r.dx(), t.g.dx(), t.b.dx(), t.u.dx(), t.v.dx()],
+        }
+    }
+
+    /// Moves `pixels` to the right.
+    fn advance(&mut self, step: &Interpolants, pixels: i32) {
+        for i in 0..3 {
+            self.edges[i] += step.edges[i] * pixels;
+        }
+
+        for i in 0..5 {
+            self.attributes[i] += step.attributes[i] * pixels as i64;
+        }
+    }
+
+    /// Attribute `index` for every lane, clamped like `Gradient::at`.
+    fn lanes(&self, step: &Interpolants, index: usize) -> Lanes<i32> {
+        let mut out = [0; LANES];
+
+        for (l, o) in out.iter_mut().enumerate() {
+            let value = self.attributes[index] + step.attributes[index] * l as i64;
+
+            *o = (value >> 12).clamp(0, 255) as i32;
+        }
+
+        out
+    }
+}
+
+/// Draws up to `LANES` pixels of a row starting at `x`, `start` holding the
+/// interpolants at `x`. Counts the pixels and texels like
+/// `rasterizer::draw_pixel`.
+fn draw_span<S: Surface>(vram: &mut S,
+                         state: &DrawState,
+                         t: &TriangleSetup,
+                         texture: Option<Texture>,
+                         (x, y): (i32, i32),
+                         count: usize,
+                         start: &Interpolants) -> DrawStats {
+    let mut stats = DrawStats::default();
+
+    let step = &Interpolants::step(t);
+
+    let mut active = [false; LANES];
+
+    for (l, a) in active.iter_mut().enumerate() {
+        let mut inside = l < count;
+
+        for i in 0..3 {
+            let w = start.edges[i] + step.edges[i] * l as i32;
+
+            inside &= w > 0 || (w == 0 && t.top_left[i]);
+        }
+
+        *a = inside;
+    }
+
+    if !active.contains(&true) {
+        return stats;
+    }
+
+    let r = start.lanes(step, 0);
+    let g = start.lanes(step, 1);
+    let b = start.lanes(step, 2);
+    let u = start.lanes(step, 3);
+    let v = start.lanes(step, 4);
+
+    let (pixels, blend) = match texture {
+        Some(texture) => {
+            let mut texels = [0u16; LANES];
+
+            for l in 0..LANES {
+                if active[l] {
+                    texels[l] = vram.fetch_texel(&texture, u[l] as u8, v[l] as u8);
+                    stats.texels += 1;
+                }
+            }
+
+            let mut blend = [false; LANES];
+
+            for l in 0..LANES {
+                // Fully black texels are transparent.
+                active[l] &= texels[l] != 0;
+                blend[l] = texels[l] & 0x8000 != 0;
+            }
+
+            let pixels = match texture.raw {
+                true => texels,
+                false => {
+                    let r = modulate(&texels, 0, &r);
+                    let g = modulate(&texels, 5, &g);
+                    let b = modulate(&texels, 10, &b);
+
+                    let mut pixels = encode(state, (x, y), &r, &g, &b);
+
+                    for l in 0..LANES {
+                        pixels[l] |= texels[l] & 0x8000;
+                    }
+
+                    pixels
+                },
+            };
+
+            (pixels, blend)
+        },
+        None => (encode(state, (x, y), &r, &g, &b), [true; LANES]),
+    };
+
+    stats.pixels = put_span(vram, state, (x, y), &active, &pixels, &blend);
+
+    stats
+}
+
+/// Lane version of `rasterizer::modulate` for one channel.
+fn modulate(texels: &Lanes<u16>, shift: u16, color: &Lanes<i32>) -> Lanes<i32> {
+    let mut out = [0; LANES];
+
+    for l in 0..LANES {
+        let t = ((texels[l] >> shift) & 0x1f) as i32;
+
+        out[l] = ((t * color[l]) >> 4).min(0xff);
+    }
+
+    out
+}
+
+/// Lane version of `rasterizer::encode`.
+fn encode(state: &DrawState,
+          (x, y): (i32, i32),
+          r: &Lanes<i32>,
+          g: &Lanes<i32>,
+          b: &Lanes<i32>) -> Lanes<u16> {
+    let mut offset = [0; LANES];
+
+    if state.dither {
+        let row = DITHER_MATRIX[((y / state.scale) & 3) as usize];
+
+        for (l, o) in offset.iter_mut().enumerate() {
+            *o = row[(((x + l as i32) / state.scale) & 3) as usize];
+        }
+    }
+
+    let mut out = [0; LANES];
+
+    for l in 0..LANES {
+        let channel = |c: i32| ((c + offset[l]).clamp(0, 0xff) >> 3) as u16;
+
+        out[l] = channel(r[l]) | (channel(g[l]) << 5) | (channel(b[l]) << 10);
+    }
+
+    out
+}
+
+/// Lane version of `rasterizer::put_pixel`, returns the number of pixels
+/// written.
+fn put_span<S: Surface>(vram: &mut S,
+                        state: &DrawState,
+                        (x, y): (i32, i32),
+                        active: &Lanes<bool>,
+                        pixels: &Lanes<u16>,
+                        blend: &Lanes<bool>) -> u32 {
+    let mut back = [0u16; LANES];
+
+    for l in 0..LANES {
+        if active[l] {
+            let px = (x + l as i32) as u16;
+
+            back[l] = match state.scale {
+                1 => vram.load16(px, y as u16),
+                _ => vram.load_upscaled(px, y as u16),
+            };
+        }
+    }
+
+    let mut out = *pixels;
+
+    if let Some(mode) = state.semi_transparency {
+        let blended = blend_span(mode as u8, &back, pixels);
+
+        for l in 0..LANES {
+            if blend[l] {
+                out[l] = blended[l];
+            }
+        }
+    }
+
+    let mask = (state.set_mask as u16) << 15;
+
+    let mut written = 0;
+
+    for l in 0..LANES {
+        let protected = state.check_mask && back[l] & 0x8000 != 0;
+
+        if active[l] && !protected {
+            let px = (x + l as i32) as u16;
+
+            match state.scale {
+                1 => vram.store16(px, y as u16, out[l] | mask),
+                _ => vram.store_upscaled(px, y as u16, out[l] | mask),
+            }
+
+            written += 1;
+        }
+    }
+
+    written
+}
+
+/// Lane version of `SemiTransparency::blend`, `mode` being the GP0 0xE1
+/// field.
+fn blend_span(mode: u8, back: &Lanes<u16>, front: &Lanes<u16>) -> Lanes<u16> {
+    let mut out = [0; LANES];
+
+    for shift in [0, 5, 10] {
+        for l in 0..LANES {
+            let b = ((back[l] >> shift) & 0x1f) as i32;
+            let f = ((front[l] >> shift) & 0x1f) as i32;
+
+            let c = match mode {
+                0 => (b + f) >> 1,
+                1 => b + f,
+                2 => b - f,
+                _ => b + (f >> 2),
+            };
+
+            out[l] |= (c.clamp(0, 0x1f) as u16) << shift;
+        }
+    }
+
+    for l in 0..LANES {
+        out[l] |= front[l] & 0x8000;
+    }
+
+    out
+}
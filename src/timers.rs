@@ -0,0 +1,313 @@
+use gpu::VideoSignals;
+use irq::{Interrupt, InterruptState};
+
+/// The three root counters.
+pub struct Timers {
+    timers: [Timer; 3],
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers {
+            timers: [
+                Timer::new(Interrupt::Timer0),
+                Timer::new(Interrupt::Timer1),
+                Timer::new(Interrupt::Timer2),
+            ],
+        }
+    }
+
+    pub fn load(&mut self, offset: u32) -> u32 {
+        let timer = &mut self.timers[(offset >> 4) as usize];
+
+        match offset & 0xf {
+            0 => timer.counter as u32,
+            4 => timer.mode(),
+            8 => timer.target as u32,
+            _ => panic!("Unhandled timer register {:x}", offset),
+        }
+    }
+
+    pub fn store(&mut self, offset: u32, value: u32) {
+        let timer = &mut self.timers[(offset >> 4) as usize];
+
+        match offset & 0xf {
+            0 => timer.counter = value as u16,
+            4 => timer.set_mode(value),
+            8 => timer.target = value as u16,
+            _ => panic!("Unhandled timer register {:x}", offset),
+        }
+    }
+
+    /// Advances the counters by `cycles` CPU cycles. Timer 0 can count GPU
+    /// dots and timer 1 scanlines, and both can synchronise on the blanking
+    /// periods.
+    pub fn tick(&mut self, cycles: u32, video: &VideoSignals, irq: &mut InterruptState) {
+        let [ref mut t0, ref mut t1, ref mut t2] = self.timers;
+
+        t0.sync_blank(video.hblank_starts, video.in_hblank);
+        let ticks = match t0.clock_source & 1 {
+            0 => cycles,
+            _ => video.dot_clocks,
+        };
+        t0.count(ticks, irq);
+
+        t1.sync_blank(video.vblank_starts, video.in_vblank);
+        let ticks = match t1.clock_source & 1 {
+            0 => cycles,
+            _ => video.hblank_starts,
+        };
+        t1.count(ticks, irq);
+
+        // Timer 2 has no blanking input, sync modes 0 and 3 stop it.
+        t2.paused = t2.sync && (t2.sync_mode == 0 || t2.sync_mode == 3);
+        let ticks = match t2.clock_source & 2 {
+            0 => cycles,
+            _ => {
+                let cycles = cycles + t2.divider_fraction;
+
+                t2.divider_fraction = cycles % 8;
+
+                cycles / 8
+            },
+        };
+        t2.count(ticks, irq);
+    }
+}
+
+struct Timer {
+    counter: u16,
+    target: u16,
+
+    sync: bool,
+    sync_mode: u8,
+    reset_on_target: bool,
+    irq_on_target: bool,
+    irq_on_overflow: bool,
+    irq_repeat: bool,
+    irq_toggle: bool,
+    clock_source: u8,
+
+    /// Mode bit 10, the IRQ output is active low.
+    irq_line: bool,
+    /// Set when a one-shot IRQ has fired, until the mode is written again.
+    irq_fired: bool,
+
+    target_reached: bool,
+    overflow_reached: bool,
+
+    paused: bool,
+    /// Set once a mode 3 timer has seen its blanking period, it then
+    /// counts freely while the mode still reads back as synchronised.
+    free_run: bool,
+
+    /// Leftover cycles for timer 2's system clock / 8 source.
+    divider_fraction: u32,
+
+    interrupt: Interrupt,
+}
+
+impl Timer {
+    fn new(interrupt: Interrupt) -> Timer {
+        Timer {
+            counter: 0,
+            target: 0,
+
+            sync: false,
+            sync_mode: 0,
+            reset_on_target: false,
+            irq_on_target: false,
+            irq_on_overflow: false,
+            irq_repeat: false,
+            irq_toggle: false,
+            clock_source: 0,
+
+            irq_line: true,
+            irq_fired: false,
+
+            target_reached: false,
+            overflow_reached: false,
+
+            paused: false,
+            free_run: false,
+
+            divider_fraction: 0,
+
+            interrupt,
+        }
+    }
+
+    /// Reading the mode acknowledges the reached flags.
+    fn mode(&mut self) -> u32 {
+        let mut r = 0;
+
+        r |= self.sync as u32;
+        r |= (self.sync_mode as u32) << 1;
+        r |= (self.reset_on_target as u32) << 3;
+        r |= (self.irq_on_target as u32) << 4;
+        r |= (self.irq_on_overflow as u32) << 5;
+        r |= (self.irq_repeat as u32) << 6;
+        r |= (self.irq_toggle as u32) << 7;
+        r |= (self.clock_source as u32) << 8;
+        r |= (self.irq_line as u32) << 10;
+        r |= (self.target_reached as u32) << 11;
+        r |= (self.overflow_reached as u32) << 12;
+
+        self.target_reached = false;
+        self.overflow_reached = false;
+
+        r
+    }
+
+    fn set_mode(&mut self, value: u32) {
+        self.sync = value & 1 != 0;
+        self.sync_mode = ((value >> 1) & 3) as u8;
+        self.reset_on_target = (value >> 3) & 1 != 0;
+        self.irq_on_target = (value >> 4) & 1 != 0;
+        self.irq_on_overflow = (value >> 5) & 1 != 0;
+        self.irq_repeat = (value >> 6) & 1 != 0;
+        self.irq_toggle = (value >> 7) & 1 != 0;
+        self.clock_source = ((value >> 8) & 3) as u8;
+
+        // Writing the mode restarts the counter.
+        self.counter = 0;
+        self.irq_line = true;
+        self.irq_fired = false;
+
+        // Mode 3 waits for the first blanking period before counting.
+        self.paused = self.sync && self.sync_mode == 3;
+        self.free_run = false;
+    }
+
+    /// Applies the sync modes of timers 0 and 1. `starts` is the number of
+    /// blanking periods that began during this tick.
+    fn sync_blank(&mut self, starts: u32, in_blank: bool) {
+        if !self.sync || self.free_run {
+            self.paused = false;
+            return;
+        }
+
+        match self.sync_mode {
+            // Pause during blanking.
+            0 => self.paused = in_blank,
+            // Reset at the start of blanking.
+            1 => {
+                if starts > 0 {
+                    self.counter = 0;
+                }
+                self.paused = false;
+            },
+            // Reset at the start of blanking, pause outside of it.
+            2 => {
+                if starts > 0 {
+                    self.counter = 0;
+                }
+                self.paused = !in_blank;
+            },
+            // Wait for a blanking period then switch to free run.
+            _ => {
+                if starts > 0 {
+                    self.free_run = true;
+                    self.paused = false;
+                }
+            },
+        }
+    }
+
+    fn count(&mut self, ticks: u32, irq: &mut InterruptState) {
+        if self.paused {
+            return;
+        }
+
+        for _ in 0..ticks {
+            let (counter, overflow) = self.counter.overflowing_add(1);
+
+            self.counter = counter;
+
+            if overflow {
+                self.overflow_reached = true;
+
+                if self.irq_on_overflow {
+                    self.trigger_irq(irq);
+                }
+            }
+
+            if self.counter == self.target {
+                self.target_reached = true;
+
+                if self.irq_on_target {
+                    self.trigger_irq(irq);
+                }
+
+                if self.reset_on_target {
+                    self.counter = 0;
+                }
+            }
+        }
+    }
+
+    fn trigger_irq(&mut self, irq: &mut InterruptState) {
+        if self.irq_fired && !self.irq_repeat {
+            return;
+        }
+
+        self.irq_fired = true;
+
+        match self.irq_toggle {
+            true => self.irq_line = !self.irq_line,
+            false => self.irq_line = false,
+        }
+
+        // The interrupt controller latches the falling edge.
+        if !self.irq_line {
+            irq.assert(self.interrupt);
+        }
+
+        // In pulse mode the line goes back up right away.
+        if !self.irq_toggle {
+            self.irq_line = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gpu::VideoSignals;
+    use irq::InterruptState;
+
+    use super::Timers;
+
+    fn signals(vblank_starts: u32, in_vblank: bool) -> VideoSignals {
+        VideoSignals {
+            dot_clocks: 0,
+            hblank_starts: 0,
+            vblank_starts,
+            in_hblank: false,
+            in_vblank,
+        }
+    }
+
+    #[test]
+    fn mode_3_keeps_sync_bits_after_blank() {
+        let mut timers = Timers::new();
+        let mut irq = InterruptState::new();
+
+        // Timer 1, sync enabled in mode 3.
+        timers.store(0x14, 0x0007);
+
+        timers.tick(100, &signals(0, false), &mut irq);
+
+        assert_eq!(timers.load(0x10), 0);
+
+        timers.tick(100, &signals(1, true), &mut irq);
+        timers.tick(100, &signals(0, false), &mut irq);
+
+        assert_eq!(timers.load(0x10), 200);
+        assert_eq!(timers.load(0x14) & 7, 7);
+
+        // Later blanking periods don't stop it.
+        timers.tick(100, &signals(1, true), &mut irq);
+
+        assert_eq!(timers.load(0x10), 300);
+    }
+}
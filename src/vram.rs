@@ -0,0 +1,402 @@
+
+use rasterizer::{DrawArea, Texture};
+use texture_cache::TextureCache;
+use texture_pack::{Replacement, TexturePack};
+
+pub const VRAM_WIDTH: usize = 1024;
+pub const VRAM_HEIGHT: usize = 512;
+
+/// Size of the blocks VRAM writes are tracked in, a 4bit texture page.
+const BLOCK_WIDTH: usize = 64;
+const BLOCK_HEIGHT: usize = 256;
+
+/// Pixel storage the rasterizer draws into, either the whole VRAM or a tile
+/// of it. Coordinates wrap like for `VRam`.
+pub trait Surface {
+    fn scale(&self) -> u16;
+
+    fn load16(&self, x: u16, y: u16) -> u16;
+
+    fn store16(&mut self, x: u16, y: u16, value: u16);
+
+    fn load_upscaled(&self, x: u16, y: u16) -> u16;
+
+    fn store_upscaled(&mut self, x: u16, y: u16, value: u16);
+
+    /// Samples a texel for the rasterizer.
+    fn fetch_texel(&mut self, texture: &Texture, u: u8, v: u8) -> u16 where Self: Sized {
+        texture.fetch(self, u, v)
+    }
+
+    /// Texel of a replacement image for a pixel, `original` being the one
+    /// fetched from VRAM.
+    fn fetch_replacement(&self, _replacement: &Replacement, original: u16, _texcoord: (i32, i32)) -> u16 {
+        original
+    }
+}
+
+/// The GPU's 1MB of video RAM, addressed as a 1024x512 grid of 16bit pixels.
+pub struct VRam {
+    data: Vec<u16>,
+    /// Internal resolution multiplier, 1 when rendering at the native
+    /// resolution.
+    scale: u16,
+    /// Copy of VRAM `scale` times wider and taller that primitives are also
+    /// drawn into. Empty at the native resolution.
+    upscaled: Vec<u16>,
+    /// Texture cache the rasterizer samples through, `None` when texels are
+    /// read straight from VRAM.
+    cache: Option<TextureCache>,
+    /// Texture dumping and replacement, `None` when disabled.
+    pack: Option<TexturePack>,
+    /// Write counter of the last write to each block, so that the texture
+    /// pack only rehashes textures whose VRAM changed.
+    block_writes: Vec<u64>,
+    write_time: u64,
+}
+
+impl VRam {
+    pub fn new() -> VRam {
+        let data = vec![0; VRAM_WIDTH * VRAM_HEIGHT];
+
+        VRam {
+            data,
+            scale: 1,
+            upscaled: Vec::new(),
+            cache: None,
+            pack: None,
+            block_writes: vec![0; (VRAM_WIDTH / BLOCK_WIDTH) * (VRAM_HEIGHT / BLOCK_HEIGHT)],
+            write_time: 0,
+        }
+    }
+
+    /// Coordinates wrap around at the VRAM edges like on the real hardware.
+    /// This only touches the native resolution VRAM.
+    pub fn load16(&self, x: u16, y: u16) -> u16 {
+        self.data[VRam::index(x, y)]
+    }
+
+    pub fn store16(&mut self, x: u16, y: u16, value: u16) {
+        self.data[VRam::index(x, y)] = value;
+
+        let (x, y) = VRam::wrap(x, y);
+
+        self.block_writes[VRam::block(x as usize, y as usize)] = self.write_time;
+    }
+
+    /// All the pixels, row after row.
+    pub fn pixels(&self) -> &[u16] {
+        &self.data
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u16] {
+        self.touch_all();
+
+        &mut self.data
+    }
+
+    /// Current write counter, see `written_since`.
+    pub fn write_time(&self) -> u64 {
+        self.write_time
+    }
+
+    /// True if part of `area` may have been written after a texture lookup
+    /// made when the write counter was `time`.
+    pub fn written_since(&self, area: &DrawArea, time: u64) -> bool {
+        let left = area.left.max(0) as usize / BLOCK_WIDTH;
+        let right = area.right.min(VRAM_WIDTH as i32 - 1).max(0) as usize / BLOCK_WIDTH;
+        let top = area.top.max(0) as usize / BLOCK_HEIGHT;
+        let bottom = area.bottom.min(VRAM_HEIGHT as i32 - 1).max(0) as usize / BLOCK_HEIGHT;
+
+        (top..=bottom).any(|y| {
+            (left..=right).any(|x| self.block_writes[VRam::block(x * BLOCK_WIDTH, y * BLOCK_HEIGHT)] > time)
+        })
+    }
+
+    fn touch_all(&mut self) {
+        let time = self.write_time;
+
+        for write in self.block_writes.iter_mut() {
+            *write = time;
+        }
+    }
+
+    fn block(x: usize, y: usize) -> usize {
+        (y / BLOCK_HEIGHT) * (VRAM_WIDTH / BLOCK_WIDTH) + x / BLOCK_WIDTH
+    }
+
+    pub fn scale(&self) -> u16 {
+        self.scale
+    }
+
+    /// Changes the internal resolution, the upscaled copy starts as a blown
+    /// up version of the native VRAM. `scale` must be a power of two.
+    pub fn set_scale(&mut self, scale: u16) {
+        self.scale = scale;
+
+        self.upscaled = match scale {
+            1 => Vec::new(),
+            _ => vec![0; VRAM_WIDTH * VRAM_HEIGHT * (scale as usize).pow(2)],
+        };
+
+        if scale > 1 {
+            for y in 0..VRAM_HEIGHT as u16 {
+                for x in 0..VRAM_WIDTH as u16 {
+                    self.replicate(x, y);
+                }
+            }
+        }
+    }
+
+    pub fn set_texture_cache(&mut self, enabled: bool) {
+        self.cache = match enabled {
+            true => Some(TextureCache::new()),
+            false => None,
+        };
+    }
+
+    pub fn has_texture_cache(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    pub fn invalidate_texture_cache(&mut self) {
+        if let Some(ref mut cache) = self.cache {
+            cache.invalidate();
+        }
+    }
+
+    pub fn set_texture_pack(&mut self, pack: Option<TexturePack>) {
+        self.pack = pack;
+    }
+
+    pub fn has_texture_pack(&self) -> bool {
+        self.pack.is_some()
+    }
+
+    /// Replacement for the page of `texture` a primitive samples, see
+    /// `TexturePack::lookup`.
+    pub fn replace_texture(&mut self, texture: &Texture) -> Option<Replacement> {
+        let mut pack = self.pack.take()?;
+
+        let replacement = pack.lookup(self, texture);
+
+        self.pack = Some(pack);
+
+        // Writes from now on happen after the lookup.
+        self.write_time += 1;
+
+        replacement
+    }
+
+    /// Accesses the upscaled VRAM, coordinates are in upscaled pixels.
+    pub fn load_upscaled(&self, x: u16, y: u16) -> u16 {
+        self.upscaled[self.upscaled_index(x, y)]
+    }
+
+    pub fn store_upscaled(&mut self, x: u16, y: u16, value: u16) {
+        let index = self.upscaled_index(x, y);
+
+        self.upscaled[index] = value;
+    }
+
+    /// Copies a native pixel over its whole block of the upscaled VRAM,
+    /// for writes that don't go through the rasterizer.
+    pub fn replicate(&mut self, x: u16, y: u16) {
+        if self.scale == 1 {
+            return;
+        }
+
+        let value = self.load16(x, y);
+
+        let (x, y) = VRam::wrap(x, y);
+
+        let scale = self.scale;
+
+        for dy in 0..scale {
+            for dx in 0..scale {
+                self.store_upscaled(x * scale + dx, y * scale + dy, value);
+            }
+        }
+    }
+
+    /// Copies the upscaled block of a native pixel to another one, so that
+    /// VRAM to VRAM copies keep the high resolution details.
+    pub fn copy_upscaled(&mut self, (sx, sy): (u16, u16), (dx, dy): (u16, u16), mask: u16) {
+        if self.scale == 1 {
+            return;
+        }
+
+        let (sx, sy) = VRam::wrap(sx, sy);
+        let (dx, dy) = VRam::wrap(dx, dy);
+
+        let scale = self.scale;
+
+        for y in 0..scale {
+            for x in 0..scale {
+                let value = self.load_upscaled(sx * scale + x, sy * scale + y);
+
+                self.store_upscaled(dx * scale + x, dy * scale + y, value | mask);
+            }
+        }
+    }
+
+    fn index(x: u16, y: u16) -> usize {
+        let (x, y) = VRam::wrap(x, y);
+
+        y as usize * VRAM_WIDTH + x as usize
+    }
+
+    fn wrap(x: u16, y: u16) -> (u16, u16) {
+        (x & (VRAM_WIDTH as u16 - 1), y & (VRAM_HEIGHT as u16 - 1))
+    }
+
+    fn upscaled_index(&self, x: u16, y: u16) -> usize {
+        upscaled_index(self.scale, x, y)
+    }
+
+    /// Splits rows `top` to `bottom` of VRAM into at most `count` bands
+    /// that can be drawn into from different threads. Each tile can read any
+    /// pixel but only write to its own rows.
+    ///
+    /// # Safety
+    ///
+    /// VRAM must not be used otherwise, moved or dropped until every tile
+    /// is done with, and while the tiles are used concurrently no tile may
+    /// read a pixel that another tile writes.
+    pub unsafe fn tiles(&mut self, top: u16, bottom: u16, count: usize) -> Vec<Tile> {
+        let (top, bottom) = (top as usize, bottom.min(VRAM_HEIGHT as u16 - 1) as usize);
+
+        let rows = (bottom + 1 - top).div_ceil(count);
+
+        self.touch_all();
+
+        let data = self.data.as_mut_ptr();
+        let upscaled = self.upscaled.as_mut_ptr();
+
+        (top..=bottom).step_by(rows).map(|top| {
+            Tile {
+                data,
+                upscaled,
+                scale: self.scale,
+                top: top as u16,
+                bottom: (top + rows - 1).min(bottom) as u16,
+            }
+        }).collect()
+    }
+}
+
+fn upscaled_index(scale: u16, x: u16, y: u16) -> usize {
+    let width = VRAM_WIDTH * scale as usize;
+    let height = VRAM_HEIGHT * scale as usize;
+
+    let x = x as usize & (width - 1);
+    let y = y as usize & (height - 1);
+
+    y * width + x
+}
+
+/// Band of VRAM rows returned by `VRam::tiles`.
+pub struct Tile {
+    data: *mut u16,
+    upscaled: *mut u16,
+    scale: u16,
+    /// Native rows this tile may write, inclusive.
+    pub top: u16,
+    pub bottom: u16,
+}
+
+// Tiles only ever write to their own rows, see `VRam::tiles`.
+unsafe impl Send for Tile {}
+
+impl Surface for VRam {
+    fn scale(&self) -> u16 {
+        VRam::scale(self)
+    }
+
+    fn load16(&self, x: u16, y: u16) -> u16 {
+        VRam::load16(self, x, y)
+    }
+
+    fn store16(&mut self, x: u16, y: u16, value: u16) {
+        VRam::store16(self, x, y, value)
+    }
+
+    fn load_upscaled(&self, x: u16, y: u16) -> u16 {
+        VRam::load_upscaled(self, x, y)
+    }
+
+    fn store_upscaled(&mut self, x: u16, y: u16, value: u16) {
+        VRam::store_upscaled(self, x, y, value)
+    }
+
+    fn fetch_texel(&mut self, texture: &Texture, u: u8, v: u8) -> u16 {
+        let data = &self.data;
+
+        match self.cache {
+            Some(ref mut cache) => cache.fetch(texture, u, v, |x, y| data[VRam::index(x, y)]),
+            None => texture.fetch(self, u, v),
+        }
+    }
+
+    fn fetch_replacement(&self, replacement: &Replacement, original: u16, texcoord: (i32, i32)) -> u16 {
+        match self.pack {
+            Some(ref pack) => pack.fetch(replacement, original, texcoord),
+            None => original,
+        }
+    }
+}
+
+impl Surface for Tile {
+    fn scale(&self) -> u16 {
+        self.scale
+    }
+
+    fn load16(&self, x: u16, y: u16) -> u16 {
+        unsafe { *self.data.add(VRam::index(x, y)) }
+    }
+
+    fn store16(&mut self, x: u16, y: u16, value: u16) {
+        let (_, row) = VRam::wrap(x, y);
+
+        assert!(row >= self.top && row <= self.bottom, "Write outside of tile");
+
+        unsafe { *self.data.add(VRam::index(x, y)) = value }
+    }
+
+    fn load_upscaled(&self, x: u16, y: u16) -> u16 {
+        unsafe { *self.upscaled.add(upscaled_index(self.scale, x, y)) }
+    }
+
+    fn store_upscaled(&mut self, x: u16, y: u16, value: u16) {
+        let index = upscaled_index(self.scale, x, y);
+
+        let row = (index / (VRAM_WIDTH * self.scale as usize)) as u16 / self.scale;
+
+        assert!(row >= self.top && row <= self.bottom, "Write outside of tile");
+
+        unsafe { *self.upscaled.add(index) = value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VRam;
+
+    fn bands(vram: &mut VRam, top: u16, bottom: u16, count: usize) -> Vec<(u16, u16)> {
+        let tiles = unsafe { vram.tiles(top, bottom, count) };
+
+        tiles.iter().map(|tile| (tile.top, tile.bottom)).collect()
+    }
+
+    #[test]
+    fn tiles_split_the_requested_rows() {
+        let mut vram = VRam::new();
+
+        assert_eq!(bands(&mut vram, 100, 109, 4), [(100, 102), (103, 105), (106, 108), (109, 109)]);
+
+        // Fewer rows than threads.
+        assert_eq!(bands(&mut vram, 5, 6, 4), [(5, 5), (6, 6)]);
+
+        assert_eq!(bands(&mut vram, 0, 511, 2), [(0, 255), (256, 511)]);
+    }
+}